@@ -0,0 +1,203 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Async SPI connection for tokio based services
+
+use crate::worker::{stopped, OwnedSegments, Worker};
+use crate::{Result, Segment, SpiConfig, SpiStream, Stream};
+use spidev::SpiModeFlags;
+use tokio::sync::oneshot;
+
+/// Struct for communicating with an SPI device from async code
+///
+/// The stream lives on a dedicated worker thread, so blocking ioctls never
+/// stall the executor. Operations are queued and run one at a time.
+pub struct AsyncConnection {
+    worker: Worker,
+}
+
+impl AsyncConnection {
+    /// Async SPI connection constructor
+    ///
+    /// # Argument
+    ///
+    /// `stream` - Stream to move onto the worker thread
+    pub fn new(stream: Box<dyn Stream + Send>) -> Self {
+        Self {
+            worker: Worker::spawn(stream),
+        }
+    }
+
+    /// Convenience constructor for creating an AsyncConnection with a SPIDEV
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to SPI device
+    /// `bpw` - Bits per word
+    /// `max_speed` - Max speed in Hz
+    /// `mode` - SPI Mode
+    pub fn from_path(
+        path: String,
+        bpw: u8,
+        max_speed: u32,
+        mode: SpiModeFlags,
+    ) -> Result<AsyncConnection> {
+        Ok(Self::new(Box::new(SpiStream::new(
+            path, bpw, max_speed, mode,
+        )?)))
+    }
+
+    // Run `f` against the stream on the worker thread and wait for its result
+    pub(crate) async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut dyn Stream) -> Result<R> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.worker.submit(move |stream| {
+            let _ = tx.send(f(stream));
+        })?;
+        rx.await.map_err(|_| stopped())?
+    }
+
+    /// Write data to a SPI device
+    ///
+    /// # Argument
+    ///
+    /// `data` - Data to write
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        let data = data.to_vec();
+        self.run(move |stream| stream.write(&data)).await
+    }
+
+    /// Read data from a SPI device
+    ///
+    /// # Argument
+    ///
+    /// `len` - Amount of Data to read
+    pub async fn read(&self, len: usize) -> Result<Vec<u8>> {
+        self.run(move |stream| stream.read(len)).await
+    }
+
+    /// Write data to a SPI device and read the results
+    ///
+    /// # Argument
+    ///
+    /// `data` - Data to write
+    pub async fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let data = data.to_vec();
+        self.run(move |stream| stream.transfer(&data)).await
+    }
+
+    /// Perform several segments as one transaction with chip select held
+    ///
+    /// Returns the received data of every read and transfer segment, in order
+    ///
+    /// # Argument
+    ///
+    /// `segments` - Segments to perform, in order
+    pub async fn transaction(&self, segments: &[Segment<'_>]) -> Result<Vec<Vec<u8>>> {
+        let segments = OwnedSegments::new(segments);
+        self.run(move |stream| stream.transaction(&segments.segments()))
+            .await
+    }
+
+    /// Set the SPI mode of the device
+    ///
+    /// # Argument
+    ///
+    /// `mode` - SPI Mode
+    pub async fn set_mode(&self, mode: SpiModeFlags) -> Result<()> {
+        self.run(move |stream| stream.set_mode(mode)).await
+    }
+
+    /// Set the max clock speed of the device
+    ///
+    /// # Argument
+    ///
+    /// `max_speed` - Max speed in Hz
+    pub async fn set_speed(&self, max_speed: u32) -> Result<()> {
+        self.run(move |stream| stream.set_speed(max_speed)).await
+    }
+
+    /// Set the bits per word of the device
+    ///
+    /// # Argument
+    ///
+    /// `bpw` - Bits per word
+    pub async fn set_bits_per_word(&self, bpw: u8) -> Result<()> {
+        self.run(move |stream| stream.set_bits_per_word(bpw)).await
+    }
+
+    /// Settings the device is actually using, as reported back by the driver
+    pub async fn config(&self) -> Result<SpiConfig> {
+        self.run(|stream| stream.config()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Expectation, MockStream};
+
+    #[tokio::test]
+    async fn operations_run_on_the_worker() {
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x06]),
+            Expectation::read(&[0x42]),
+            Expectation::transfer(&[0x05, 0x00], &[0xff, 0x01]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x9f]),
+                Expectation::read(&[0xef, 0x40]),
+            ]),
+        ]);
+        let connection = AsyncConnection::new(Box::new(mock.clone()));
+        connection.write(&[0x06]).await.unwrap();
+        assert_eq!(connection.read(1).await.unwrap(), vec![0x42]);
+        assert_eq!(
+            connection.transfer(&[0x05, 0x00]).await.unwrap(),
+            vec![0xff, 0x01]
+        );
+        let rx = connection
+            .transaction(&[Segment::write(&[0x9f]), Segment::read(2)])
+            .await
+            .unwrap();
+        assert_eq!(rx, vec![vec![0xef, 0x40]]);
+        mock.done();
+    }
+
+    #[tokio::test]
+    async fn settings_run_on_the_worker() {
+        let connection = AsyncConnection::new(Box::new(MockStream::new(vec![])));
+        connection.set_mode(SpiModeFlags::SPI_MODE_1).await.unwrap();
+        connection.set_speed(2_000_000).await.unwrap();
+        connection.set_bits_per_word(16).await.unwrap();
+        let config = connection.config().await.unwrap();
+        assert_eq!(config.mode, SpiModeFlags::SPI_MODE_1);
+        assert_eq!(config.max_speed_hz, 2_000_000);
+        assert_eq!(config.bits_per_word, 16);
+    }
+
+    #[tokio::test]
+    async fn errors_are_returned() {
+        let connection =
+            AsyncConnection::new(Box::new(MockStream::new(vec![Expectation::write(&[0x01])])));
+        assert!(matches!(
+            connection.write(&[0x02]).await,
+            Err(Error::Protocol(_))
+        ));
+    }
+}
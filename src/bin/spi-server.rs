@@ -0,0 +1,68 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Server exposing a local SPI device to `RemoteStream` clients over TCP
+//
+//     spi-server <device> <address> [max_speed_hz] [mode] [bits_per_word]
+//
+// e.g. `spi-server /dev/spidev0.0 0.0.0.0:7878 1000000 0`
+
+use spi_rs::{remote, Connection};
+use spidev::SpiModeFlags;
+use std::env;
+use std::net::TcpListener;
+use std::process;
+
+const USAGE: &str = "usage: spi-server <device> <address> [max_speed_hz] [mode] [bits_per_word]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 || args.len() > 5 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let max_speed = parse(args.get(2), 1_000_000);
+    let mode = parse(args.get(3), 0);
+    let bpw = parse(args.get(4), 8);
+
+    let mut connection = Connection::from_path(
+        args[0].clone(),
+        bpw,
+        max_speed,
+        SpiModeFlags::from_bits_truncate(mode),
+    )
+    .unwrap_or_else(|e| fail(&e));
+    let listener = TcpListener::bind(&args[1]).unwrap_or_else(|e| fail(&e));
+    eprintln!("serving {} on {}", args[0], args[1]);
+    if let Err(e) = remote::serve(&mut connection, &listener) {
+        fail(&e);
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: Option<&String>, default: T) -> T {
+    match arg {
+        Some(arg) => arg.parse().unwrap_or_else(|_| {
+            eprintln!("invalid argument {}\n{}", arg, USAGE);
+            process::exit(2)
+        }),
+        None => default,
+    }
+}
+
+fn fail(error: &dyn std::fmt::Display) -> ! {
+    eprintln!("spi-server: {}", error);
+    process::exit(1)
+}
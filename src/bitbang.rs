@@ -0,0 +1,381 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// SPI master clocked in software on plain GPIO lines
+
+use crate::gpio::{InputLine, OutputLine};
+use crate::shared_bus;
+use crate::{unsupported, ChipSelect, Result, Segment, SegmentKind, SpiConfig, Stream};
+use spidev::SpiModeFlags;
+use std::cell::RefCell;
+use std::thread;
+use std::time::Duration;
+
+struct Lines<O, I> {
+    sclk: O,
+    mosi: O,
+    miso: I,
+}
+
+// Chip select line as driven for the current mode
+struct CsLine<'a, O> {
+    line: &'a mut O,
+    mode: SpiModeFlags,
+}
+
+impl<O: OutputLine> CsLine<'_, O> {
+    fn set(&mut self, selected: bool) -> Result<()> {
+        if self.mode.contains(SpiModeFlags::SPI_NO_CS) {
+            return Ok(());
+        }
+        self.line
+            .set_value(selected == self.mode.contains(SpiModeFlags::SPI_CS_HIGH))
+    }
+}
+
+impl<O: OutputLine> ChipSelect for CsLine<'_, O> {
+    fn select(&mut self) -> Result<()> {
+        self.set(true)
+    }
+
+    fn deselect(&mut self) -> Result<()> {
+        self.set(false)
+    }
+}
+
+/// Stream clocking SPI in software over four GPIO lines
+///
+/// Supports all four SPI modes, `SPI_LSB_FIRST`, `SPI_CS_HIGH` and
+/// `SPI_NO_CS`, with 8 bits per word. The clock runs at most at the speed set
+/// through the half-period delay, slower in practice due to GPIO latency.
+pub struct BitBangStream<O, I> {
+    lines: RefCell<Lines<O, I>>,
+    cs: RefCell<O>,
+    mode: SpiModeFlags,
+    half_period: Duration,
+}
+
+impl<O: OutputLine, I: InputLine> BitBangStream<O, I> {
+    /// BitBangStream constructor, leaves the clock idle and the device deselected
+    ///
+    /// # Arguments
+    ///
+    /// `sclk` - Clock output
+    /// `mosi` - Data output
+    /// `miso` - Data input
+    /// `cs` - Chip select output
+    /// `mode` - SPI Mode
+    pub fn new(sclk: O, mosi: O, miso: I, cs: O, mode: SpiModeFlags) -> Result<Self> {
+        let mut stream = Self {
+            lines: RefCell::new(Lines { sclk, mosi, miso }),
+            cs: RefCell::new(cs),
+            mode,
+            half_period: Duration::from_micros(5),
+        };
+        stream.idle()?;
+        Ok(stream)
+    }
+
+    /// Set the delay between clock edges
+    ///
+    /// # Argument
+    ///
+    /// `half_period` - Half of the clock period
+    pub fn half_period(mut self, half_period: Duration) -> Self {
+        self.half_period = half_period;
+        self
+    }
+
+    fn has(&self, flag: SpiModeFlags) -> bool {
+        self.mode.contains(flag)
+    }
+
+    // Drive the clock to its idle level and deselect the device
+    fn idle(&mut self) -> Result<()> {
+        let cpol = self.has(SpiModeFlags::SPI_CPOL);
+        self.lines.get_mut().sclk.set_value(cpol)?;
+        CsLine {
+            line: self.cs.get_mut(),
+            mode: self.mode,
+        }
+        .deselect()
+    }
+
+    // Clock one byte out and in
+    fn exchange(&self, lines: &mut Lines<O, I>, tx: u8, half_period: Duration) -> Result<u8> {
+        let cpol = self.has(SpiModeFlags::SPI_CPOL);
+        let cpha = self.has(SpiModeFlags::SPI_CPHA);
+        let lsb_first = self.has(SpiModeFlags::SPI_LSB_FIRST);
+        let mut rx = 0u8;
+        for i in 0..8 {
+            let bit = if lsb_first { i } else { 7 - i };
+            let out = tx & (1 << bit) != 0;
+            // Data is shifted out on one edge and sampled on the other,
+            // CPHA selecting whether sampling happens on the leading edge
+            if !cpha {
+                lines.mosi.set_value(out)?;
+            }
+            delay(half_period);
+            lines.sclk.set_value(!cpol)?;
+            if cpha {
+                lines.mosi.set_value(out)?;
+            } else if lines.miso.value()? {
+                rx |= 1 << bit;
+            }
+            delay(half_period);
+            lines.sclk.set_value(cpol)?;
+            if cpha && lines.miso.value()? {
+                rx |= 1 << bit;
+            }
+        }
+        Ok(rx)
+    }
+
+    fn exchange_all(
+        &self,
+        lines: &mut Lines<O, I>,
+        tx: &[u8],
+        half_period: Duration,
+    ) -> Result<Vec<u8>> {
+        tx.iter()
+            .map(|&byte| self.exchange(lines, byte, half_period))
+            .collect()
+    }
+
+    // Run `f` with the device selected, releasing chip select even if `f` fails
+    fn selected<R>(&self, f: impl FnOnce(&mut Lines<O, I>) -> Result<R>) -> Result<R> {
+        let mut lines = self.lines.borrow_mut();
+        let mut cs = self.cs.borrow_mut();
+        let mut cs = CsLine {
+            line: &mut *cs,
+            mode: self.mode,
+        };
+        shared_bus::selected(&mut cs, || f(&mut lines))
+    }
+}
+
+fn delay(duration: Duration) {
+    if duration > Duration::from_secs(0) {
+        thread::sleep(duration);
+    }
+}
+
+impl<O: OutputLine, I: InputLine> Stream for BitBangStream<O, I> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.transfer(data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.transfer(&vec![0; len])
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.selected(|lines| self.exchange_all(lines, data, self.half_period))
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        if segments
+            .iter()
+            .any(|s| s.bits_per_word != 0 && s.bits_per_word != 8)
+        {
+            return Err(unsupported("bits per word other than 8"));
+        }
+        let mut rx = Vec::new();
+        // Chip select is released between groups ending in a `cs_change` segment
+        for (i, group) in segments
+            .split_inclusive(|segment| segment.cs_change)
+            .enumerate()
+        {
+            if i > 0 {
+                delay(self.half_period);
+            }
+            self.selected(|lines| {
+                for segment in group {
+                    let half_period = match segment.speed_hz {
+                        0 => self.half_period,
+                        speed => half_period(speed),
+                    };
+                    let data = match segment.kind {
+                        SegmentKind::Write(data) | SegmentKind::Transfer(data) => {
+                            self.exchange_all(lines, data, half_period)?
+                        }
+                        SegmentKind::Read(len) => {
+                            self.exchange_all(lines, &vec![0; len], half_period)?
+                        }
+                    };
+                    if segment.is_read() {
+                        rx.push(data);
+                    }
+                    delay(Duration::from_micros(segment.delay_usecs.into()));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(rx)
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.mode = mode;
+        self.idle()
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.half_period = half_period(max_speed);
+        Ok(())
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        if bpw != 8 {
+            return Err(unsupported("bits per word other than 8"));
+        }
+        Ok(())
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        let half_period = self.half_period.as_nanos();
+        Ok(SpiConfig {
+            bits_per_word: 8,
+            max_speed_hz: match half_period {
+                0 => 0,
+                ns => (500_000_000 / ns).min(u32::MAX as u128) as u32,
+            },
+            mode: self.mode,
+        })
+    }
+}
+
+// Half period of a clock running at `speed` Hz
+fn half_period(speed: u32) -> Duration {
+    match speed {
+        0 => Duration::from_secs(0),
+        speed => Duration::from_nanos(500_000_000 / u64::from(speed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::sync::{Arc, Mutex};
+
+    const SCLK: usize = 0;
+    const MOSI: usize = 1;
+    const CS: usize = 2;
+
+    // Levels of the output lines, with MISO wired back to MOSI
+    #[derive(Default)]
+    struct Pins {
+        levels: [bool; 3],
+        cs_log: Vec<bool>,
+    }
+
+    #[derive(Clone)]
+    struct Pin(Arc<Mutex<Pins>>, usize);
+
+    impl OutputLine for Pin {
+        fn set_value(&mut self, high: bool) -> Result<()> {
+            let mut pins = self.0.lock().unwrap();
+            pins.levels[self.1] = high;
+            if self.1 == CS {
+                pins.cs_log.push(high);
+            }
+            Ok(())
+        }
+    }
+
+    struct Loopback(Arc<Mutex<Pins>>);
+
+    impl InputLine for Loopback {
+        fn value(&mut self) -> Result<bool> {
+            Ok(self.0.lock().unwrap().levels[MOSI])
+        }
+    }
+
+    fn wired(mode: SpiModeFlags) -> (BitBangStream<Pin, Loopback>, Arc<Mutex<Pins>>) {
+        let pins = Arc::new(Mutex::new(Pins::default()));
+        let stream = BitBangStream::new(
+            Pin(pins.clone(), SCLK),
+            Pin(pins.clone(), MOSI),
+            Loopback(pins.clone()),
+            Pin(pins.clone(), CS),
+            mode,
+        )
+        .unwrap()
+        .half_period(Duration::from_secs(0));
+        (stream, pins)
+    }
+
+    #[test]
+    fn loopback_in_every_mode() {
+        for &mode in &[
+            SpiModeFlags::SPI_MODE_0,
+            SpiModeFlags::SPI_MODE_1,
+            SpiModeFlags::SPI_MODE_2,
+            SpiModeFlags::SPI_MODE_3,
+            SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_LSB_FIRST,
+        ] {
+            let (stream, pins) = wired(mode);
+            assert_eq!(stream.transfer(&[0xa5, 0x3c]).unwrap(), vec![0xa5, 0x3c]);
+            let pins = pins.lock().unwrap();
+            assert_eq!(pins.levels[SCLK], mode.contains(SpiModeFlags::SPI_CPOL));
+            assert_eq!(pins.cs_log, [true, false, true]);
+        }
+    }
+
+    #[test]
+    fn chip_select_modes() {
+        let (stream, pins) = wired(SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_CS_HIGH);
+        stream.transfer(&[0x01]).unwrap();
+        assert_eq!(pins.lock().unwrap().cs_log, [false, true, false]);
+
+        let (stream, pins) = wired(SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_NO_CS);
+        stream.transfer(&[0x01]).unwrap();
+        assert!(pins.lock().unwrap().cs_log.is_empty());
+    }
+
+    #[test]
+    fn transaction_releases_chip_select_at_cs_change() {
+        let (stream, pins) = wired(SpiModeFlags::SPI_MODE_0);
+        let rx = stream
+            .transaction(&[
+                Segment::write(&[0x06]).cs_change(true),
+                Segment::transfer(&[0x02, 0x00]),
+                Segment::read(1).cs_change(true),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0x02, 0x00], vec![0x00]]);
+        assert_eq!(
+            pins.lock().unwrap().cs_log,
+            [true, false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn only_8_bits_per_word() {
+        let (mut stream, _) = wired(SpiModeFlags::SPI_MODE_0);
+        assert!(matches!(
+            stream.set_bits_per_word(16),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            stream.transaction(&[Segment::write(&[0]).bits_per_word(9)]),
+            Err(Error::Unsupported(_))
+        ));
+        stream.set_speed(1_000_000).unwrap();
+        assert_eq!(stream.config().unwrap().max_speed_hz, 1_000_000);
+    }
+}
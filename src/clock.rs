@@ -0,0 +1,104 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Time sources for streams, real or simulated
+
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Source of time used for delays and deadlines
+pub trait Clock: Send + Sync {
+    /// Current time
+    fn now(&self) -> Instant;
+
+    /// Wait for `duration` to pass
+    ///
+    /// # Argument
+    ///
+    /// `duration` - Time to wait
+    fn sleep(&self, duration: Duration);
+}
+
+/// Wall clock time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if duration > Duration::from_secs(0) {
+            thread::sleep(duration);
+        }
+    }
+}
+
+/// Simulated time, which only passes when advanced
+///
+/// Sleeping advances the clock instantly, so code waiting on it runs as fast
+/// as it can while seeing the same time on every run.
+#[derive(Debug)]
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    /// VirtualClock constructor, starting at zero
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    fn elapsed_mut(&self) -> MutexGuard<'_, Duration> {
+        self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Time passed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed_mut()
+    }
+
+    /// Let time pass
+    ///
+    /// # Argument
+    ///
+    /// `duration` - Time to pass
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed_mut() += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,541 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Register map generation from YAML or TOML device descriptions
+//
+// A description lists the registers of one device:
+//
+//     registers:
+//       - name: CTRL_REG1
+//         address: 0x20
+//         width: 8
+//         reset: 0x07
+//         access: RW
+//         description: Control register 1
+//         fields:
+//           - { name: ODR, bits: "7:4", description: Output data rate }
+//           - { name: LPEN, bits: 3 }
+//
+// and is turned into a `register_map!` invocation, typically from a build
+// script:
+//
+//     spi_rs::codegen::generate_file(
+//         "regs/lis3dh.yaml",
+//         Path::new(&env::var("OUT_DIR").unwrap()).join("lis3dh.rs"),
+//     )
+//     .unwrap();
+//     println!("cargo:rerun-if-changed=regs/lis3dh.yaml");
+//
+// with the driver doing `include!(concat!(env!("OUT_DIR"), "/lis3dh.rs"));`.
+// Register names are converted to CamelCase and field names to snake_case,
+// after which they must be unique and must not be Rust keywords.
+
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Errors raised while generating a register map
+#[derive(Debug)]
+pub enum CodegenError {
+    /// A file could not be read or written
+    Io(io::Error),
+    /// The description could not be parsed
+    Parse(String),
+    /// The description is inconsistent
+    Invalid(String),
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::Io(source) => source.fmt(f),
+            CodegenError::Parse(msg) => write!(f, "failed to parse description: {}", msg),
+            CodegenError::Invalid(msg) => write!(f, "invalid description: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+impl From<io::Error> for CodegenError {
+    fn from(error: io::Error) -> Self {
+        CodegenError::Io(error)
+    }
+}
+
+/// Format of a device description file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+}
+
+/// Access type of a register
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Access {
+    RO,
+    WO,
+    RW,
+    W1C,
+}
+
+/// Bit position of a field, a single bit or an `"msb:lsb"` range
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Bits {
+    Bit(u32),
+    Range(String),
+}
+
+/// Field of a register
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FieldDescription {
+    pub name: String,
+    pub bits: Bits,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// Register of a device
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RegisterDescription {
+    pub name: String,
+    pub address: u32,
+    /// Width in bits, 8, 16 or 32
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default)]
+    pub reset: u32,
+    pub access: Access,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<FieldDescription>,
+}
+
+fn default_width() -> u32 {
+    8
+}
+
+/// Description of a device's registers
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DeviceDescription {
+    pub registers: Vec<RegisterDescription>,
+}
+
+impl DeviceDescription {
+    /// Parse a device description
+    ///
+    /// # Arguments
+    ///
+    /// `source` - Contents of the description
+    /// `format` - Format of the description
+    pub fn parse(source: &str, format: Format) -> Result<Self, CodegenError> {
+        match format {
+            Format::Yaml => {
+                serde_yaml::from_str(source).map_err(|e| CodegenError::Parse(e.to_string()))
+            }
+            Format::Toml => toml::from_str(source).map_err(|e| CodegenError::Parse(e.to_string())),
+        }
+    }
+
+    /// Read and parse a description file, picking the format from its extension
+    ///
+    /// # Argument
+    ///
+    /// `path` - Path to a `.yaml`, `.yml` or `.toml` file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, CodegenError> {
+        let path = path.as_ref();
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => {
+                return Err(CodegenError::Invalid(format!(
+                    "unknown description format of {}",
+                    path.display()
+                )))
+            }
+        };
+        Self::parse(&fs::read_to_string(path)?, format)
+    }
+
+    /// Generate the `register_map!` invocation for this device
+    pub fn generate(&self) -> Result<String, CodegenError> {
+        let mut out = String::new();
+        out.push_str("// Generated by spi-rs from a device description, do not edit\n\n");
+        out.push_str("spi_rs::register_map! {\n");
+        let mut names = HashSet::new();
+        for register in &self.registers {
+            generate_register(&mut out, register, &mut names)?;
+        }
+        out.push_str("}\n");
+        Ok(out)
+    }
+}
+
+/// Generate a register map source file from a description file
+///
+/// Nothing is printed, so a build script should emit
+/// `cargo:rerun-if-changed` for `input` itself.
+///
+/// # Arguments
+///
+/// `input` - Path to the description
+/// `output` - Path of the Rust file to write
+pub fn generate_file(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> Result<(), CodegenError> {
+    let source = DeviceDescription::from_file(&input)?.generate()?;
+    fs::write(output, source)?;
+    Ok(())
+}
+
+// `names` holds the struct names generated so far
+fn generate_register(
+    out: &mut String,
+    register: &RegisterDescription,
+    names: &mut HashSet<String>,
+) -> Result<(), CodegenError> {
+    let invalid =
+        |msg: String| CodegenError::Invalid(format!("register {}: {}", register.name, msg));
+    let raw = match register.width {
+        8 => "u8",
+        16 => "u16",
+        32 => "u32",
+        width => return Err(invalid(format!("unsupported width {}", width))),
+    };
+    if u64::from(register.reset) >> register.width != 0 {
+        return Err(invalid(format!(
+            "reset value {:#x} does not fit",
+            register.reset
+        )));
+    }
+
+    let name = camel_case(&register.name);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(invalid("name does not start with a letter".to_string()));
+    }
+    if is_keyword(&name) {
+        return Err(invalid(format!("name {} is a Rust keyword", name)));
+    }
+    if !names.insert(name.clone()) {
+        return Err(invalid(format!(
+            "name {} is used by another register",
+            name
+        )));
+    }
+
+    write_doc(out, "    ", &register.description);
+    let _ = writeln!(
+        out,
+        "    pub {} @ {:#04x}: {} = {:#04x}, {:?} {{",
+        name, register.address, raw, register.reset, register.access
+    );
+    let mut used = 0u64;
+    // Accessors generated so far, each field adds a getter and a setter
+    let mut accessors = HashSet::new();
+    for field in &register.fields {
+        let field_name = snake_case(&field.name);
+        if field_name.is_empty() || is_keyword(&field_name) {
+            return Err(invalid(format!(
+                "field name {:?} is not a valid identifier",
+                field.name
+            )));
+        }
+        let setter = format!("set_{}", field_name);
+        if accessors.contains(&field_name) || accessors.contains(&setter) {
+            return Err(invalid(format!(
+                "field {} clashes with another field",
+                field_name
+            )));
+        }
+        accessors.insert(setter);
+        accessors.insert(field_name.clone());
+
+        let (msb, lsb) = bit_range(&field.bits).ok_or_else(|| {
+            invalid(format!(
+                "field {} has invalid bits {:?}",
+                field.name, field.bits
+            ))
+        })?;
+        if msb >= register.width {
+            return Err(invalid(format!(
+                "field {} exceeds the register",
+                field.name
+            )));
+        }
+        let mask = ((1u64 << (msb - lsb + 1)) - 1) << lsb;
+        if used & mask != 0 {
+            return Err(invalid(format!(
+                "field {} overlaps another field",
+                field.name
+            )));
+        }
+        used |= mask;
+
+        write_doc(out, "        ", &field.description);
+        if msb == lsb {
+            let _ = writeln!(out, "        {}: {},", field_name, lsb);
+        } else {
+            let _ = writeln!(out, "        {}: {}..={},", field_name, lsb, msb);
+        }
+    }
+    out.push_str("    }\n");
+    Ok(())
+}
+
+fn write_doc(out: &mut String, indent: &str, doc: &Option<String>) {
+    if let Some(doc) = doc {
+        for line in doc.lines() {
+            let _ = writeln!(out, "{}/// {}", indent, line.trim());
+        }
+    }
+}
+
+// (msb, lsb) of a field
+fn bit_range(bits: &Bits) -> Option<(u32, u32)> {
+    match bits {
+        Bits::Bit(bit) => Some((*bit, *bit)),
+        Bits::Range(range) => {
+            let mut parts = range.split(':').map(|part| part.trim().parse::<u32>());
+            let range = match (parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(bit)), None, None) => (bit, bit),
+                (Some(Ok(a)), Some(Ok(b)), None) => (a.max(b), a.min(b)),
+                _ => return None,
+            };
+            Some(range)
+        }
+    }
+}
+
+// Strict and reserved keywords, which cannot name a register or field
+const KEYWORDS: &[&str] = &[
+    "Self", "abstract", "as", "async", "await", "become", "box", "break", "const", "continue",
+    "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(&name)
+}
+
+fn words(name: &str) -> impl Iterator<Item = &str> {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+fn camel_case(name: &str) -> String {
+    words(name)
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().map(|c| c.to_ascii_uppercase());
+            first
+                .into_iter()
+                .chain(chars.map(|c| c.to_ascii_lowercase()))
+                .collect::<String>()
+        })
+        .collect()
+}
+
+fn snake_case(name: &str) -> String {
+    let name = words(name)
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("_");
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "
+registers:
+  - name: WHO_AM_I
+    address: 0x0f
+    reset: 0x33
+    access: RO
+  - name: CTRL_REG1
+    address: 0x20
+    reset: 0x07
+    access: RW
+    description: Control register 1
+    fields:
+      - { name: ODR, bits: \"7:4\", description: Output data rate }
+      - { name: LPEN, bits: 3 }
+";
+
+    const TOML: &str = "
+[[registers]]
+name = \"threshold\"
+address = 0x32
+width = 16
+access = \"WO\"
+
+[[registers.fields]]
+name = \"VALUE\"
+bits = \"0:15\"
+";
+
+    fn register(name: &str, width: u32, fields: &[(&str, Bits)]) -> DeviceDescription {
+        DeviceDescription {
+            registers: vec![RegisterDescription {
+                name: name.to_string(),
+                address: 0x10,
+                width,
+                reset: 0,
+                access: Access::RW,
+                description: None,
+                fields: fields
+                    .iter()
+                    .map(|(name, bits)| FieldDescription {
+                        name: name.to_string(),
+                        bits: bits.clone(),
+                        description: None,
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    fn invalid(description: &DeviceDescription) -> String {
+        match description.generate() {
+            Err(CodegenError::Invalid(msg)) => msg,
+            other => panic!("expected an invalid description, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_yaml() {
+        let device = DeviceDescription::parse(YAML, Format::Yaml).unwrap();
+        assert_eq!(device.registers.len(), 2);
+        let ctrl = &device.registers[1];
+        assert_eq!(ctrl.address, 0x20);
+        assert_eq!(ctrl.width, 8);
+        assert_eq!(ctrl.access, Access::RW);
+        assert_eq!(ctrl.fields[0].bits, Bits::Range("7:4".to_string()));
+        assert_eq!(ctrl.fields[1].bits, Bits::Bit(3));
+        assert_eq!(
+            device.generate().unwrap(),
+            "// Generated by spi-rs from a device description, do not edit\n\n\
+             spi_rs::register_map! {\n    \
+             pub WhoAmI @ 0x0f: u8 = 0x33, RO {\n    }\n    \
+             /// Control register 1\n    \
+             pub CtrlReg1 @ 0x20: u8 = 0x07, RW {\n        \
+             /// Output data rate\n        \
+             odr: 4..=7,\n        \
+             lpen: 3,\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn parses_toml() {
+        let device = DeviceDescription::parse(TOML, Format::Toml).unwrap();
+        let threshold = &device.registers[0];
+        assert_eq!(threshold.width, 16);
+        assert_eq!(threshold.access, Access::WO);
+        let source = device.generate().unwrap();
+        assert!(source.contains("pub Threshold @ 0x32: u16 = 0x00, WO {\n        value: 0..=15,\n"));
+    }
+
+    #[test]
+    fn generates_files() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("spi-rs-codegen-{}.yaml", std::process::id()));
+        let output = dir.join(format!("spi-rs-codegen-{}.rs", std::process::id()));
+        fs::write(&input, YAML).unwrap();
+        generate_file(&input, &output).unwrap();
+        let expected = DeviceDescription::parse(YAML, Format::Yaml)
+            .unwrap()
+            .generate()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            DeviceDescription::parse("registers: 3", Format::Yaml),
+            Err(CodegenError::Parse(_))
+        ));
+        assert!(matches!(
+            DeviceDescription::parse("registers = [", Format::Toml),
+            Err(CodegenError::Parse(_))
+        ));
+        assert!(matches!(
+            DeviceDescription::from_file("regs.json"),
+            Err(CodegenError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_overlapping_fields() {
+        let device = register(
+            "CTRL",
+            8,
+            &[("A", Bits::Range("3:0".to_string())), ("B", Bits::Bit(3))],
+        );
+        assert!(invalid(&device).contains("overlaps"));
+    }
+
+    #[test]
+    fn rejects_bad_widths() {
+        assert!(invalid(&register("CTRL", 12, &[])).contains("unsupported width"));
+        let device = register("CTRL", 8, &[("A", Bits::Range("8:6".to_string()))]);
+        assert!(invalid(&device).contains("exceeds"));
+        let mut device = register("CTRL", 8, &[]);
+        device.registers[0].reset = 0x100;
+        assert!(invalid(&device).contains("does not fit"));
+        let device = register("CTRL", 8, &[("A", Bits::Range("7:x".to_string()))]);
+        assert!(invalid(&device).contains("invalid bits"));
+    }
+
+    #[test]
+    fn rejects_keywords() {
+        let device = register("CTRL", 8, &[("TYPE", Bits::Bit(0))]);
+        assert!(invalid(&device).contains("not a valid identifier"));
+        assert!(invalid(&register("self", 8, &[])).contains("keyword"));
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let mut device = register("CTRL_REG", 8, &[]);
+        device
+            .registers
+            .extend(register("ctrl-reg", 8, &[]).registers);
+        assert!(invalid(&device).contains("another register"));
+        let device = register("CTRL", 8, &[("MODE", Bits::Bit(0)), ("mode", Bits::Bit(1))]);
+        assert!(invalid(&device).contains("clashes"));
+        let device = register(
+            "CTRL",
+            8,
+            &[("MODE", Bits::Bit(0)), ("SET_MODE", Bits::Bit(1))],
+        );
+        assert!(invalid(&device).contains("clashes"));
+    }
+}
@@ -0,0 +1,256 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Table driven CRC computation
+//
+// Parameters follow the Rocksoft model used by the CRC catalogue. Each preset
+// carries the catalogue's check value, the CRC of the ASCII string
+// "123456789", which `Crc::verify_check` compares against.
+
+/// Parameters of a CRC algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrcParams {
+    /// Width in bits, 1 to 32
+    pub width: u32,
+    /// Generator polynomial, without the leading term
+    pub poly: u32,
+    /// Initial register value
+    pub init: u32,
+    /// Whether input bytes are processed least significant bit first
+    pub refin: bool,
+    /// Whether the register is reflected before the final XOR
+    pub refout: bool,
+    /// Value XORed into the result
+    pub xorout: u32,
+    /// CRC of "123456789"
+    pub check: u32,
+}
+
+/// CRC-8 (SMBus)
+pub const CRC_8: CrcParams = CrcParams {
+    width: 8,
+    poly: 0x07,
+    init: 0x00,
+    refin: false,
+    refout: false,
+    xorout: 0x00,
+    check: 0xf4,
+};
+
+/// CRC-16-CCITT, with the customary 0xFFFF start value (CCITT-FALSE)
+pub const CRC_16_CCITT: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x1021,
+    init: 0xffff,
+    refin: false,
+    refout: false,
+    xorout: 0x0000,
+    check: 0x29b1,
+};
+
+/// CRC-32 as used by Ethernet and zlib
+pub const CRC_32: CrcParams = CrcParams {
+    width: 32,
+    poly: 0x04c1_1db7,
+    init: 0xffff_ffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffff_ffff,
+    check: 0xcbf4_3926,
+};
+
+/// CRC algorithm with its lookup table
+#[derive(Clone)]
+pub struct Crc {
+    params: CrcParams,
+    table: [u32; 256],
+}
+
+impl Crc {
+    /// Crc constructor, building the lookup table
+    ///
+    /// # Argument
+    ///
+    /// `params` - Parameters of the algorithm
+    pub fn new(params: CrcParams) -> Self {
+        let width = params.width.clamp(1, 32);
+        let params = CrcParams {
+            width,
+            poly: params.poly & mask(width),
+            init: params.init & mask(width),
+            xorout: params.xorout & mask(width),
+            ..params
+        };
+        let mut table = [0u32; 256];
+        for (byte, entry) in table.iter_mut().enumerate() {
+            *entry = if params.refin {
+                let poly = reflect(params.poly, width);
+                (0..8).fold(byte as u32, |crc, _| {
+                    if crc & 1 != 0 {
+                        (crc >> 1) ^ poly
+                    } else {
+                        crc >> 1
+                    }
+                })
+            } else {
+                // Register kept in the top bits, so any width shares the code
+                let poly = params.poly << (32 - width);
+                (0..8).fold((byte as u32) << 24, |crc, _| {
+                    if crc & 0x8000_0000 != 0 {
+                        (crc << 1) ^ poly
+                    } else {
+                        crc << 1
+                    }
+                })
+            };
+        }
+        Self { params, table }
+    }
+
+    /// Parameters of the algorithm
+    pub fn params(&self) -> &CrcParams {
+        &self.params
+    }
+
+    /// Number of bytes a CRC value takes in a message
+    pub fn bytes(&self) -> usize {
+        (self.params.width as usize).div_ceil(8)
+    }
+
+    /// CRC of `data`
+    ///
+    /// # Argument
+    ///
+    /// `data` - Data to checksum
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        let CrcParams {
+            width,
+            init,
+            refin,
+            refout,
+            xorout,
+            ..
+        } = self.params;
+        let crc = if refin {
+            let reg = data.iter().fold(reflect(init, width), |reg, &byte| {
+                (reg >> 8) ^ self.table[((reg ^ u32::from(byte)) & 0xff) as usize]
+            });
+            if refout {
+                reg
+            } else {
+                reflect(reg, width)
+            }
+        } else {
+            let reg = data.iter().fold(init << (32 - width), |reg, &byte| {
+                (reg << 8) ^ self.table[((reg >> 24) ^ u32::from(byte)) as usize]
+            });
+            let reg = reg >> (32 - width);
+            if refout {
+                reflect(reg, width)
+            } else {
+                reg
+            }
+        };
+        (crc ^ xorout) & mask(width)
+    }
+
+    /// Whether the algorithm reproduces the check value of its parameters
+    pub fn verify_check(&self) -> bool {
+        self.checksum(b"123456789") == self.params.check
+    }
+}
+
+// Mask of the low `width` bits
+fn mask(width: u32) -> u32 {
+    u32::MAX >> (32 - width)
+}
+
+// Reverse the low `width` bits of `value`
+fn reflect(value: u32, width: u32) -> u32 {
+    value.reverse_bits() >> (32 - width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(params: CrcParams) {
+        let crc = Crc::new(params);
+        assert_eq!(
+            crc.checksum(b"123456789"),
+            params.check,
+            "check value of {:?}",
+            params
+        );
+        assert!(crc.verify_check());
+    }
+
+    #[test]
+    fn presets() {
+        check(CRC_8);
+        check(CRC_16_CCITT);
+        check(CRC_32);
+        assert_eq!(Crc::new(CRC_8).checksum(b"123456789"), 0xf4);
+        assert_eq!(Crc::new(CRC_16_CCITT).checksum(b"123456789"), 0x29b1);
+        assert_eq!(Crc::new(CRC_32).checksum(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn reflected_16_bit() {
+        // CRC-16/KERMIT
+        check(CrcParams {
+            width: 16,
+            poly: 0x1021,
+            init: 0,
+            refin: true,
+            refout: true,
+            xorout: 0,
+            check: 0x2189,
+        });
+    }
+
+    #[test]
+    fn sub_byte_widths() {
+        // CRC-5/USB
+        check(CrcParams {
+            width: 5,
+            poly: 0x05,
+            init: 0x1f,
+            refin: true,
+            refout: true,
+            xorout: 0x1f,
+            check: 0x19,
+        });
+        // CRC-3/GSM
+        check(CrcParams {
+            width: 3,
+            poly: 0x3,
+            init: 0,
+            refin: false,
+            refout: false,
+            xorout: 0x7,
+            check: 0x4,
+        });
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(Crc::new(CRC_8).bytes(), 1);
+        assert_eq!(Crc::new(CRC_16_CCITT).bytes(), 2);
+        assert_eq!(Crc::new(CRC_32).bytes(), 4);
+        assert_eq!(Crc::new(CrcParams { width: 5, ..CRC_8 }).bytes(), 1);
+    }
+}
@@ -0,0 +1,311 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Devices signalling new data on a data-ready (DRDY) line
+
+use crate::gpio::EdgeLine;
+use crate::{Connection, Error, Operation, Result, Segment};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often a listener checks whether it has been stopped
+const STOP_CHECK: Duration = Duration::from_millis(100);
+
+/// Connection paired with the data-ready line of its device
+pub struct DataReady<L> {
+    connection: Connection,
+    line: L,
+}
+
+impl<L: EdgeLine> DataReady<L> {
+    /// DataReady constructor
+    ///
+    /// # Arguments
+    ///
+    /// `connection` - Connection to the device
+    /// `line` - Data-ready line, requested for the edge signalling new data
+    pub fn new(connection: Connection, line: L) -> Self {
+        Self { connection, line }
+    }
+
+    /// Underlying connection
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Data-ready line
+    pub fn line(&mut self) -> &mut L {
+        &mut self.line
+    }
+
+    /// Give back the connection and line
+    pub fn into_inner(self) -> (Connection, L) {
+        (self.connection, self.line)
+    }
+
+    /// Wait for the device to signal new data
+    ///
+    /// Fails with `Error::Timeout` when no edge arrives in time.
+    ///
+    /// # Argument
+    ///
+    /// `timeout` - Time to wait at most
+    pub fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        if self.line.wait_edge(timeout)? {
+            Ok(())
+        } else {
+            Err(Error::Timeout {
+                op: Operation::Read,
+            })
+        }
+    }
+
+    /// Wait for the device to signal new data, then run a transaction
+    ///
+    /// # Arguments
+    ///
+    /// `timeout` - Time to wait at most
+    /// `segments` - Segments to perform, in order
+    pub fn transaction_when_ready(
+        &mut self,
+        timeout: Duration,
+        segments: &[Segment],
+    ) -> Result<Vec<Vec<u8>>> {
+        self.wait_ready(timeout)?;
+        self.connection.transaction(segments)
+    }
+}
+
+impl<L: EdgeLine + Send + 'static> DataReady<L> {
+    /// Run `on_ready` on a background thread after every data-ready edge
+    ///
+    /// `on_ready` reads the frame through the connection it is passed and
+    /// deals with any errors itself.
+    ///
+    /// # Argument
+    ///
+    /// `on_ready` - Called with the connection on every edge
+    pub fn listen<F>(self, mut on_ready: F) -> Listener<L>
+    where
+        F: FnMut(&mut Connection) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let mut ready = self;
+        let thread = thread::Builder::new()
+            .name("spi-data-ready".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    match ready.line.wait_edge(STOP_CHECK) {
+                        Ok(true) => on_ready(&mut ready.connection),
+                        Ok(false) => {}
+                        Err(e) => return (ready, Err(e)),
+                    }
+                }
+                (ready, Ok(()))
+            })
+            .expect("failed to spawn data-ready thread");
+        Listener {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Background thread calling back on every data-ready edge
+///
+/// Dropping the listener stops the thread without waiting for it.
+pub struct Listener<L> {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<(DataReady<L>, Result<()>)>>,
+}
+
+impl<L> Listener<L> {
+    /// Stop the thread and give back the connection and line
+    ///
+    /// The result holds the error which ended the thread early, if the line
+    /// failed.
+    pub fn stop(mut self) -> (DataReady<L>, Result<()>) {
+        self.stop.store(true, Ordering::SeqCst);
+        let thread = self.thread.take().expect("listener already stopped");
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl<L> Drop for Listener<L> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    // Line whose edges and failures are sent by the test, counting those taken
+    struct ScriptedLine {
+        edges: Receiver<Result<()>>,
+        taken: Arc<AtomicUsize>,
+    }
+
+    impl EdgeLine for ScriptedLine {
+        fn wait_edge(&mut self, timeout: Duration) -> Result<bool> {
+            match self.edges.recv_timeout(timeout) {
+                Ok(edge) => {
+                    self.taken.fetch_add(1, Ordering::SeqCst);
+                    edge.map(|_| true)
+                }
+                Err(RecvTimeoutError::Timeout) => Ok(false),
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(timeout);
+                    Ok(false)
+                }
+            }
+        }
+    }
+
+    struct Fixture {
+        ready: DataReady<ScriptedLine>,
+        edges: Sender<Result<()>>,
+        taken: Arc<AtomicUsize>,
+        mock: MockStream,
+    }
+
+    fn fixture(expectations: Vec<Expectation>) -> Fixture {
+        let (edges, rx) = mpsc::channel();
+        let taken = Arc::new(AtomicUsize::new(0));
+        let mock = MockStream::new(expectations);
+        let line = ScriptedLine {
+            edges: rx,
+            taken: taken.clone(),
+        };
+        Fixture {
+            ready: DataReady::new(Connection::new(Box::new(mock.clone())), line),
+            edges,
+            taken,
+            mock,
+        }
+    }
+
+    // Wait for a background thread to bring about `condition`
+    fn eventually(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not met in time");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn wait_ready_returns_on_an_edge() {
+        let mut f = fixture(vec![]);
+        f.edges.send(Ok(())).unwrap();
+        f.ready.wait_ready(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn wait_ready_times_out_without_an_edge() {
+        let mut f = fixture(vec![]);
+        assert!(matches!(
+            f.ready.wait_ready(Duration::from_millis(10)),
+            Err(Error::Timeout {
+                op: Operation::Read
+            })
+        ));
+    }
+
+    #[test]
+    fn transaction_waits_for_the_edge() {
+        let mut f = fixture(vec![Expectation::transaction(vec![
+            Expectation::write(&[0xa8]),
+            Expectation::read(&[0x12, 0x34]),
+        ])]);
+        let segments = [Segment::write(&[0xa8]), Segment::read(2)];
+        // Without an edge the transaction is not run, leaving it expected
+        assert!(matches!(
+            f.ready
+                .transaction_when_ready(Duration::from_millis(10), &segments),
+            Err(Error::Timeout { .. })
+        ));
+        f.edges.send(Ok(())).unwrap();
+        let rx = f
+            .ready
+            .transaction_when_ready(Duration::from_secs(1), &segments)
+            .unwrap();
+        assert_eq!(rx, vec![vec![0x12, 0x34]]);
+        f.mock.done();
+    }
+
+    #[test]
+    fn listener_calls_back_once_per_edge() {
+        let f = fixture(vec![
+            Expectation::read(&[0x01]),
+            Expectation::read(&[0x02]),
+            Expectation::read(&[0x03]),
+        ]);
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let received = frames.clone();
+        let listener = f.ready.listen(move |connection| {
+            let frame = connection.read(1).unwrap();
+            received.lock().unwrap().push(frame);
+        });
+        for _ in 0..3 {
+            f.edges.send(Ok(())).unwrap();
+        }
+        eventually(|| frames.lock().unwrap().len() == 3);
+        let (mut ready, result) = listener.stop();
+        result.unwrap();
+        assert_eq!(
+            *frames.lock().unwrap(),
+            vec![vec![0x01], vec![0x02], vec![0x03]]
+        );
+        // Once stopped, edges are left for the caller
+        f.edges.send(Ok(())).unwrap();
+        assert!(ready.line().wait_edge(Duration::from_secs(1)).unwrap());
+        assert_eq!(frames.lock().unwrap().len(), 3);
+        f.mock.done();
+    }
+
+    #[test]
+    fn line_failures_are_reported() {
+        let mut f = fixture(vec![]);
+        f.edges
+            .send(Err(Error::Protocol("line gone".to_string())))
+            .unwrap();
+        assert!(matches!(
+            f.ready.wait_ready(Duration::from_secs(1)),
+            Err(Error::Protocol(_))
+        ));
+
+        let taken = f.taken.clone();
+        let listener = f.ready.listen(|_| panic!("no edge was sent"));
+        f.edges
+            .send(Err(Error::Protocol("line gone".to_string())))
+            .unwrap();
+        eventually(|| taken.load(Ordering::SeqCst) == 2);
+        let (_, result) = listener.stop();
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+}
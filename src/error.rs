@@ -0,0 +1,253 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Error type shared by all SPI streams
+
+use std::fmt;
+use std::io;
+
+/// Result type returned by SPI operations
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// SPI operation an error occurred in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Open,
+    Configure,
+    Write,
+    Read,
+    Transfer,
+    Transaction,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Operation::Open => "open",
+            Operation::Configure => "configure",
+            Operation::Write => "write",
+            Operation::Read => "read",
+            Operation::Transfer => "transfer",
+            Operation::Transaction => "transaction",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Errors raised by SPI streams
+#[derive(Debug)]
+pub enum Error {
+    /// The device could not be opened
+    Open { path: String, source: io::Error },
+    /// The device rejected a setting
+    Configure { path: String, source: io::Error },
+    /// A data operation on the device failed
+    Transfer {
+        path: String,
+        op: Operation,
+        source: io::Error,
+    },
+    /// An operation moved a different amount of data than requested
+    LengthMismatch {
+        op: Operation,
+        expected: usize,
+        actual: usize,
+    },
+    /// An operation did not complete in time
+    Timeout { op: Operation },
+    /// A polled status did not reach the awaited value in time
+    StatusTimeout { status: Vec<u8> },
+    /// An earlier operation hung and the stream can no longer be used
+    Unhealthy,
+    /// A message failed its integrity check
+    Checksum { expected: u32, actual: u32 },
+    /// The stream does not support a feature
+    Unsupported(String),
+    /// The exchange did not go as the protocol or a mock script expected
+    Protocol(String),
+    /// Any other I/O error
+    Io(io::Error),
+}
+
+impl Error {
+    /// Closest `std::io::ErrorKind` describing this error
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Open { source, .. }
+            | Error::Configure { source, .. }
+            | Error::Transfer { source, .. }
+            | Error::Io(source) => source.kind(),
+            Error::LengthMismatch { .. } => io::ErrorKind::UnexpectedEof,
+            Error::Timeout { .. } | Error::StatusTimeout { .. } => io::ErrorKind::TimedOut,
+            Error::Unhealthy => io::ErrorKind::BrokenPipe,
+            Error::Checksum { .. } => io::ErrorKind::InvalidData,
+            Error::Unsupported(_) => io::ErrorKind::Unsupported,
+            Error::Protocol(_) => io::ErrorKind::InvalidData,
+        }
+    }
+
+    /// OS error code behind this error, if any
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Open { source, .. }
+            | Error::Configure { source, .. }
+            | Error::Transfer { source, .. }
+            | Error::Io(source) => source.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Open { path, source } => write!(f, "failed to open {}: {}", path, source),
+            Error::Configure { path, source } => {
+                write!(f, "failed to configure {}: {}", path, source)
+            }
+            Error::Transfer { path, op, source } => {
+                write!(f, "{} on {} failed: {}", op, path, source)
+            }
+            Error::LengthMismatch {
+                op,
+                expected,
+                actual,
+            } => write!(f, "{} moved {} bytes, expected {}", op, actual, expected),
+            Error::Timeout { op } => write!(f, "{} timed out", op),
+            Error::StatusTimeout { status } => {
+                write!(f, "status polling timed out, last status {:02x?}", status)
+            }
+            Error::Unhealthy => write!(f, "stream is unhealthy after an operation hung"),
+            Error::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch: received {:#x}, computed {:#x}",
+                actual, expected
+            ),
+            Error::Unsupported(what) => write!(f, "{} is not supported by this stream", what),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Io(source) => source.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. }
+            | Error::Configure { source, .. }
+            | Error::Transfer { source, .. }
+            | Error::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(source) => source,
+            other => io::Error::new(other.kind(), other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Connection;
+    use spidev::SpiModeFlags;
+    use std::error::Error as _;
+
+    #[test]
+    fn kinds() {
+        let timeout = Error::Timeout {
+            op: Operation::Read,
+        };
+        assert_eq!(timeout.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            Error::StatusTimeout { status: vec![1] }.kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert_eq!(Error::Unhealthy.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            Error::Checksum {
+                expected: 1,
+                actual: 2
+            }
+            .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            Error::Unsupported("x".to_string()).kind(),
+            io::ErrorKind::Unsupported
+        );
+        assert_eq!(
+            Error::LengthMismatch {
+                op: Operation::Read,
+                expected: 2,
+                actual: 1
+            }
+            .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn os_errors_are_kept() {
+        let error = Error::Transfer {
+            path: "/dev/spidev0.0".to_string(),
+            op: Operation::Write,
+            source: io::Error::from_raw_os_error(5),
+        };
+        assert_eq!(error.raw_os_error(), Some(5));
+        assert!(error.source().is_some());
+        assert_eq!(error.to_string().split(' ').next(), Some("write"));
+        assert_eq!(Error::Unhealthy.raw_os_error(), None);
+    }
+
+    #[test]
+    fn io_conversions() {
+        let error: Error = io::Error::new(io::ErrorKind::WouldBlock, "busy").into();
+        assert!(matches!(error, Error::Io(_)));
+        let error: io::Error = error.into();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        let error: io::Error = Error::Protocol("bad".to_string()).into();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "protocol error: bad");
+    }
+
+    #[test]
+    fn open_failure() {
+        let path = "/dev/spidev-does-not-exist".to_string();
+        match Connection::from_path(path.clone(), 8, 1_000_000, SpiModeFlags::SPI_MODE_0) {
+            Err(Error::Open {
+                path: failed,
+                source,
+            }) => {
+                assert_eq!(failed, path);
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            Err(other) => panic!("unexpected error {}", other),
+            Ok(_) => panic!("opened a missing device"),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Messages protected by a CRC appended to each of them
+
+use crate::crc::Crc;
+use crate::{Connection, Endian, Error, Result, Segment};
+
+/// Connection exchanging CRC protected messages
+///
+/// Every message sent is followed by its CRC and every message received is
+/// checked against the CRC following it, failing with `Error::Checksum` on a
+/// mismatch. The CRC is sent big endian unless configured otherwise.
+pub struct FramedConnection {
+    connection: Connection,
+    crc: Crc,
+    endian: Endian,
+}
+
+impl FramedConnection {
+    /// FramedConnection constructor
+    ///
+    /// # Arguments
+    ///
+    /// `connection` - Connection to the device
+    /// `crc` - CRC algorithm protecting the messages
+    pub fn new(connection: Connection, crc: Crc) -> Self {
+        Self {
+            connection,
+            crc,
+            endian: Endian::Big,
+        }
+    }
+
+    /// Set the byte order of the CRC
+    ///
+    /// # Argument
+    ///
+    /// `endian` - Byte order of the CRC
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// CRC algorithm in use
+    pub fn crc(&self) -> &Crc {
+        &self.crc
+    }
+
+    /// Underlying connection
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Give back the underlying connection
+    pub fn into_inner(self) -> Connection {
+        self.connection
+    }
+
+    /// Send a message followed by its CRC
+    ///
+    /// # Argument
+    ///
+    /// `payload` - Message to send
+    pub fn send(&mut self, payload: &[u8]) -> Result<()> {
+        let frame = self.frame(payload);
+        self.connection.write(&frame)
+    }
+
+    /// Receive a message and check its CRC
+    ///
+    /// # Argument
+    ///
+    /// `len` - Length of the message, without the CRC
+    pub fn receive(&mut self, len: usize) -> Result<Vec<u8>> {
+        let frame = self.connection.read(len + self.crc.bytes())?;
+        self.unframe(frame)
+    }
+
+    /// Send a request and receive the response with chip select held
+    ///
+    /// # Arguments
+    ///
+    /// `request` - Message to send
+    /// `response_len` - Length of the response, without the CRC
+    pub fn exchange(&self, request: &[u8], response_len: usize) -> Result<Vec<u8>> {
+        let frame = self.frame(request);
+        let mut rx = self.connection.transaction(&[
+            Segment::write(&frame),
+            Segment::read(response_len + self.crc.bytes()),
+        ])?;
+        self.unframe(rx.pop().unwrap_or_default())
+    }
+
+    // `payload` with its CRC appended
+    fn frame(&self, payload: &[u8]) -> Vec<u8> {
+        let crc = self.crc.checksum(payload).to_be_bytes();
+        let mut crc = crc[crc.len() - self.crc.bytes()..].to_vec();
+        if self.endian == Endian::Little {
+            crc.reverse();
+        }
+        let mut frame = payload.to_vec();
+        frame.extend_from_slice(&crc);
+        frame
+    }
+
+    // Payload of a received frame, once its CRC checks out
+    fn unframe(&self, mut frame: Vec<u8>) -> Result<Vec<u8>> {
+        let split = frame.len().saturating_sub(self.crc.bytes());
+        let mut crc = frame.split_off(split);
+        if self.endian == Endian::Little {
+            crc.reverse();
+        }
+        let received = crc
+            .iter()
+            .fold(0u32, |value, &byte| (value << 8) | u32::from(byte));
+        let computed = self.crc.checksum(&frame);
+        if received != computed {
+            return Err(Error::Checksum {
+                expected: computed,
+                actual: received,
+            });
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream, CRC_16_CCITT, CRC_32};
+
+    fn framed(mock: &MockStream) -> FramedConnection {
+        FramedConnection::new(
+            Connection::new(Box::new(mock.clone())),
+            Crc::new(CRC_16_CCITT),
+        )
+    }
+
+    #[test]
+    fn send_appends_crc() {
+        let mock = MockStream::new(vec![Expectation::write(b"123456789\x29\xb1")]);
+        framed(&mock).send(b"123456789").unwrap();
+        mock.done();
+    }
+
+    #[test]
+    fn receive_checks_crc() {
+        let mock = MockStream::new(vec![
+            Expectation::read(b"123456789\x29\xb1"),
+            Expectation::read(b"123456789\x29\xb2"),
+        ]);
+        let mut connection = framed(&mock);
+        assert_eq!(connection.receive(9).unwrap(), b"123456789");
+        assert!(matches!(
+            connection.receive(9),
+            Err(Error::Checksum {
+                expected: 0x29b1,
+                actual: 0x29b2
+            })
+        ));
+        mock.done();
+    }
+
+    #[test]
+    fn exchange_is_one_transaction() {
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![
+                Expectation::write(b"123456789\x29\xb1"),
+                Expectation::read(b"123456789\x29\xb1"),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(b"123456789\x29\xb1"),
+                Expectation::read(b"123456788\x29\xb1"),
+            ]),
+        ]);
+        let connection = framed(&mock);
+        assert_eq!(connection.exchange(b"123456789", 9).unwrap(), b"123456789");
+        assert!(matches!(
+            connection.exchange(b"123456789", 9),
+            Err(Error::Checksum { .. })
+        ));
+        mock.done();
+    }
+
+    #[test]
+    fn little_endian_crc() {
+        let mock = MockStream::new(vec![Expectation::write(b"123456789\x26\x39\xf4\xcb")]);
+        FramedConnection::new(Connection::new(Box::new(mock.clone())), Crc::new(CRC_32))
+            .endian(Endian::Little)
+            .send(b"123456789")
+            .unwrap();
+        mock.done();
+    }
+}
@@ -0,0 +1,447 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// GPIO lines and software chip selects driven through them
+
+use crate::clock::Clock;
+use crate::shared_bus;
+use crate::{ChipSelect, Result, Segment, SpiConfig, SpiStream, Stream};
+use spidev::SpiModeFlags;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// GPIO line driven as an output
+pub trait OutputLine {
+    /// Drive the line high or low
+    ///
+    /// # Argument
+    ///
+    /// `high` - Level to drive
+    fn set_value(&mut self, high: bool) -> Result<()>;
+}
+
+/// GPIO line read as an input
+pub trait InputLine {
+    /// Current level of the line
+    fn value(&mut self) -> Result<bool>;
+}
+
+/// GPIO line reporting edges
+pub trait EdgeLine {
+    /// Wait for the next edge the line was requested for
+    ///
+    /// Returns `false` if no edge arrived in time.
+    ///
+    /// # Argument
+    ///
+    /// `timeout` - Time to wait at most
+    fn wait_edge(&mut self, timeout: Duration) -> Result<bool>;
+}
+
+/// Edges reported by an edge line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// Low to high transitions
+    Rising,
+    /// High to low transitions
+    Falling,
+    /// Transitions in either direction
+    Both,
+}
+
+/// Level of a chip select line while the device is selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Line is driven low to select the device
+    ActiveLow,
+    /// Line is driven high to select the device
+    ActiveHigh,
+}
+
+/// Chip select driven on a GPIO output line
+pub struct GpioChipSelect<L> {
+    line: L,
+    polarity: Polarity,
+    setup: Duration,
+    hold: Duration,
+}
+
+impl<L: OutputLine> GpioChipSelect<L> {
+    /// GpioChipSelect constructor, leaves the device deselected
+    ///
+    /// # Arguments
+    ///
+    /// `line` - Output line wired to the chip select pin
+    /// `polarity` - Level of the line while selected
+    pub fn new(mut line: L, polarity: Polarity) -> Result<Self> {
+        line.set_value(polarity == Polarity::ActiveLow)?;
+        Ok(Self {
+            line,
+            polarity,
+            setup: Duration::from_secs(0),
+            hold: Duration::from_secs(0),
+        })
+    }
+
+    /// Set the delay between asserting chip select and the first clock
+    ///
+    /// # Argument
+    ///
+    /// `setup` - Setup delay
+    pub fn setup_delay(mut self, setup: Duration) -> Self {
+        self.setup = setup;
+        self
+    }
+
+    /// Set the delay between the last clock and releasing chip select
+    ///
+    /// # Argument
+    ///
+    /// `hold` - Hold delay
+    pub fn hold_delay(mut self, hold: Duration) -> Self {
+        self.hold = hold;
+        self
+    }
+}
+
+impl<L: OutputLine> ChipSelect for GpioChipSelect<L> {
+    fn select(&mut self) -> Result<()> {
+        self.line.set_value(self.polarity == Polarity::ActiveHigh)?;
+        if self.setup > Duration::from_secs(0) {
+            thread::sleep(self.setup);
+        }
+        Ok(())
+    }
+
+    fn deselect(&mut self) -> Result<()> {
+        if self.hold > Duration::from_secs(0) {
+            thread::sleep(self.hold);
+        }
+        self.line.set_value(self.polarity == Polarity::ActiveLow)
+    }
+}
+
+/// Stream wrapper selecting the device with a software chip select
+///
+/// The chip select is asserted around every operation. A transaction is split
+/// at segments with `cs_change` set, releasing chip select in between.
+pub struct GpioCsStream<S, C> {
+    stream: S,
+    cs: RefCell<C>,
+}
+
+impl<S: Stream, C: ChipSelect> GpioCsStream<S, C> {
+    /// GpioCsStream constructor
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream for the bus, which must not drive its own chip select
+    /// `cs` - Chip select of the device
+    pub fn new(stream: S, cs: C) -> Self {
+        Self {
+            stream,
+            cs: RefCell::new(cs),
+        }
+    }
+
+    // Run `f` with the device selected, releasing chip select even if `f` fails
+    fn selected<R>(&self, f: impl FnOnce(&S) -> Result<R>) -> Result<R> {
+        let stream = &self.stream;
+        shared_bus::selected(&mut *self.cs.borrow_mut(), || f(stream))
+    }
+
+    fn selected_mut<R>(&mut self, f: impl FnOnce(&mut S) -> Result<R>) -> Result<R> {
+        let stream = &mut self.stream;
+        shared_bus::selected(self.cs.get_mut(), || f(stream))
+    }
+}
+
+impl<L: OutputLine> GpioCsStream<SpiStream, GpioChipSelect<L>> {
+    /// Convenience constructor opening a SPIDEV with `SPI_NO_CS`
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to SPI device
+    /// `bpw` - Bits per word
+    /// `max_speed` - Max speed in Hz
+    /// `mode` - SPI Mode
+    /// `cs` - Chip select of the device
+    pub fn from_path(
+        path: String,
+        bpw: u8,
+        max_speed: u32,
+        mode: SpiModeFlags,
+        cs: GpioChipSelect<L>,
+    ) -> Result<Self> {
+        let stream = SpiStream::new(path, bpw, max_speed, mode | SpiModeFlags::SPI_NO_CS)?;
+        Ok(Self::new(stream, cs))
+    }
+}
+
+impl<S: Stream, C: ChipSelect> Stream for GpioCsStream<S, C> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.selected_mut(|stream| stream.write(data))
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.selected_mut(|stream| stream.read(len))
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.selected(|stream| stream.transfer(data))
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        let mut rx = Vec::new();
+        for group in segments.split_inclusive(|segment| segment.cs_change) {
+            let mut group = group.to_vec();
+            if let Some(last) = group.last_mut() {
+                last.cs_change = false;
+            }
+            rx.extend(self.selected(|stream| stream.transaction(&group))?);
+        }
+        Ok(rx)
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.stream.set_mode(mode | SpiModeFlags::SPI_NO_CS)
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.stream.set_speed(max_speed)
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.stream.set_bits_per_word(bpw)
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        self.stream.config()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.stream.clock()
+    }
+}
+
+#[cfg(feature = "gpio")]
+mod cdev {
+    use super::{Edge, EdgeLine, GpioChipSelect, InputLine, OutputLine, Polarity};
+    use crate::{Error, Result};
+    use gpio_cdev::{Chip, EventRequestFlags, LineEventHandle, LineHandle, LineRequestFlags};
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    pub(crate) fn gpio_error(error: gpio_cdev::Error) -> Error {
+        Error::Io(io::Error::other(error))
+    }
+
+    impl OutputLine for LineHandle {
+        fn set_value(&mut self, high: bool) -> Result<()> {
+            LineHandle::set_value(self, high as u8).map_err(gpio_error)
+        }
+    }
+
+    impl InputLine for LineHandle {
+        fn value(&mut self) -> Result<bool> {
+            Ok(self.get_value().map_err(gpio_error)? != 0)
+        }
+    }
+
+    impl InputLine for LineEventHandle {
+        fn value(&mut self) -> Result<bool> {
+            Ok(self.get_value().map_err(gpio_error)? != 0)
+        }
+    }
+
+    impl EdgeLine for LineEventHandle {
+        fn wait_edge(&mut self, timeout: Duration) -> Result<bool> {
+            let mut fd = libc::pollfd {
+                fd: self.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            // SAFETY: `fd` is a single valid pollfd for the duration of the call
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                -1 => Err(Error::Io(io::Error::last_os_error())),
+                0 => Ok(false),
+                _ => {
+                    self.get_event().map_err(gpio_error)?;
+                    Ok(true)
+                }
+            }
+        }
+    }
+
+    /// Request a line of a GPIO character device as an input
+    ///
+    /// # Arguments
+    ///
+    /// `chip` - Path to the GPIO chip, e.g. `/dev/gpiochip0`
+    /// `offset` - Offset of the line on the chip
+    pub fn input_line(chip: &str, offset: u32) -> Result<LineHandle> {
+        Chip::new(chip)
+            .and_then(|mut chip| chip.get_line(offset))
+            .and_then(|line| line.request(LineRequestFlags::INPUT, 0, "spi-rs"))
+            .map_err(gpio_error)
+    }
+
+    /// Request edge events on a line of a GPIO character device
+    ///
+    /// # Arguments
+    ///
+    /// `chip` - Path to the GPIO chip, e.g. `/dev/gpiochip0`
+    /// `offset` - Offset of the line on the chip
+    /// `edge` - Edges to report
+    pub fn edge_line(chip: &str, offset: u32, edge: Edge) -> Result<LineEventHandle> {
+        let flags = match edge {
+            Edge::Rising => EventRequestFlags::RISING_EDGE,
+            Edge::Falling => EventRequestFlags::FALLING_EDGE,
+            Edge::Both => EventRequestFlags::BOTH_EDGES,
+        };
+        Chip::new(chip)
+            .and_then(|mut chip| chip.get_line(offset))
+            .and_then(|line| line.events(LineRequestFlags::INPUT, flags, "spi-rs"))
+            .map_err(gpio_error)
+    }
+
+    impl GpioChipSelect<LineHandle> {
+        /// Convenience constructor requesting a line of a GPIO character device
+        ///
+        /// # Arguments
+        ///
+        /// `chip` - Path to the GPIO chip, e.g. `/dev/gpiochip0`
+        /// `offset` - Offset of the line on the chip
+        /// `polarity` - Level of the line while selected
+        pub fn from_chip(chip: &str, offset: u32, polarity: Polarity) -> Result<Self> {
+            let line = Chip::new(chip)
+                .and_then(|mut chip| chip.get_line(offset))
+                .and_then(|line| {
+                    line.request(
+                        LineRequestFlags::OUTPUT,
+                        (polarity == Polarity::ActiveLow) as u8,
+                        "spi-rs",
+                    )
+                })
+                .map_err(gpio_error)?;
+            GpioChipSelect::new(line, polarity)
+        }
+    }
+}
+
+#[cfg(feature = "gpio")]
+pub use self::cdev::{edge_line, input_line};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Expectation, MockStream};
+    use std::sync::Mutex;
+
+    // Output line logging every level driven
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<bool>>>);
+
+    impl Recorder {
+        fn levels(&self) -> Vec<bool> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl OutputLine for Recorder {
+        fn set_value(&mut self, high: bool) -> Result<()> {
+            self.0.lock().unwrap().push(high);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn polarity() {
+        let line = Recorder::default();
+        let mut cs = GpioChipSelect::new(line.clone(), Polarity::ActiveLow).unwrap();
+        cs.select().unwrap();
+        cs.deselect().unwrap();
+        assert_eq!(line.levels(), [true, false, true]);
+
+        let line = Recorder::default();
+        let mut cs = GpioChipSelect::new(line.clone(), Polarity::ActiveHigh).unwrap();
+        cs.select().unwrap();
+        cs.deselect().unwrap();
+        assert_eq!(line.levels(), [false, true, false]);
+    }
+
+    #[test]
+    fn chip_select_wraps_each_operation() {
+        let line = Recorder::default();
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x01]),
+            Expectation::read(&[0x02]),
+            Expectation::transfer(&[0x03], &[0x04]),
+        ]);
+        let cs = GpioChipSelect::new(line.clone(), Polarity::ActiveLow).unwrap();
+        let mut stream = GpioCsStream::new(mock.clone(), cs);
+        stream.write(&[0x01]).unwrap();
+        assert_eq!(stream.read(1).unwrap(), vec![0x02]);
+        assert_eq!(stream.transfer(&[0x03]).unwrap(), vec![0x04]);
+        // A failed operation still releases chip select
+        assert!(matches!(stream.write(&[0x05]), Err(Error::Protocol(_))));
+        assert_eq!(
+            line.levels(),
+            [true, false, true, false, true, false, true, false, true]
+        );
+        mock.done();
+    }
+
+    #[test]
+    fn transactions_split_at_cs_change() {
+        let line = Recorder::default();
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![Expectation::write(&[0x06])]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x02, 0x00]),
+                Expectation::read(&[0xaa]),
+            ]),
+        ]);
+        let cs = GpioChipSelect::new(line.clone(), Polarity::ActiveLow).unwrap();
+        let stream = GpioCsStream::new(mock.clone(), cs);
+        let rx = stream
+            .transaction(&[
+                Segment::write(&[0x06]).cs_change(true),
+                Segment::write(&[0x02, 0x00]),
+                Segment::read(1),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0xaa]]);
+        assert_eq!(line.levels(), [true, false, true, false, true]);
+        mock.done();
+    }
+
+    #[test]
+    fn mode_keeps_no_cs() {
+        let mock = MockStream::new(vec![]);
+        let cs = GpioChipSelect::new(Recorder::default(), Polarity::ActiveLow).unwrap();
+        let mut stream = GpioCsStream::new(mock.clone(), cs);
+        stream.set_mode(SpiModeFlags::SPI_MODE_3).unwrap();
+        assert_eq!(
+            mock.config().unwrap().mode,
+            SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_NO_CS
+        );
+    }
+}
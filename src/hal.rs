@@ -0,0 +1,244 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// embedded-hal SPI traits for Connection
+//
+// `SpiDevice` maps an operation list onto one multi-segment transaction, so
+// chip select stays asserted for the whole list. `SpiBus` issues every call as
+// its own transfer; open the device with `SPI_NO_CS` and drive chip select
+// separately when using it.
+
+use crate::{Connection, Error, Operation, Result, Segment};
+use embedded_hal::spi::{self, ErrorKind, ErrorType, SpiBus, SpiDevice};
+
+impl spi::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Protocol(_) => ErrorKind::FrameFormat,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for Connection {
+    type Error = Error;
+}
+
+// Owned copy of an operation list, ready to be issued as segments
+pub(crate) struct Plan {
+    steps: Vec<Step>,
+}
+
+enum Step {
+    Write(Vec<u8>),
+    Read(usize),
+    Transfer(Vec<u8>),
+    Delay(u16),
+}
+
+impl Plan {
+    pub(crate) fn new(operations: &[spi::Operation<'_, u8>]) -> Self {
+        let mut steps = Vec::with_capacity(operations.len());
+        for operation in operations {
+            match operation {
+                spi::Operation::Read(buf) => steps.push(Step::Read(buf.len())),
+                spi::Operation::Write(buf) => steps.push(Step::Write(buf.to_vec())),
+                spi::Operation::Transfer(read, write) => {
+                    let mut tx = write.to_vec();
+                    tx.resize(read.len().max(write.len()), 0);
+                    steps.push(Step::Transfer(tx));
+                }
+                spi::Operation::TransferInPlace(buf) => steps.push(Step::Transfer(buf.to_vec())),
+                spi::Operation::DelayNs(ns) => {
+                    // A segment delay is at most u16::MAX microseconds
+                    let mut usecs = u64::from(*ns).div_ceil(1000);
+                    while usecs > 0 {
+                        let chunk = usecs.min(u64::from(u16::MAX));
+                        steps.push(Step::Delay(chunk as u16));
+                        usecs -= chunk;
+                    }
+                }
+            }
+        }
+        Self { steps }
+    }
+
+    pub(crate) fn segments(&self) -> Vec<Segment<'_>> {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                Step::Write(data) => Segment::write(data),
+                Step::Read(len) => Segment::read(*len),
+                Step::Transfer(data) => Segment::transfer(data),
+                Step::Delay(usecs) => Segment::delay(*usecs),
+            })
+            .collect()
+    }
+
+    // Copy the buffers returned by the transaction into the operations' read buffers
+    pub(crate) fn finish(
+        operations: &mut [spi::Operation<'_, u8>],
+        rx: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        let mut rx = rx.into_iter();
+        for operation in operations {
+            let buf: &mut [u8] = match operation {
+                spi::Operation::Read(buf) | spi::Operation::TransferInPlace(buf) => buf,
+                spi::Operation::Transfer(read, _) => read,
+                spi::Operation::Write(_) | spi::Operation::DelayNs(_) => continue,
+            };
+            fill(buf, &rx.next().unwrap_or_default(), Operation::Transaction)?;
+        }
+        Ok(())
+    }
+}
+
+// Copy received data into a caller's buffer, which the stream must have filled
+pub(crate) fn fill(buf: &mut [u8], data: &[u8], op: Operation) -> Result<()> {
+    if data.len() < buf.len() {
+        return Err(Error::LengthMismatch {
+            op,
+            expected: buf.len(),
+            actual: data.len(),
+        });
+    }
+    buf.copy_from_slice(&data[..buf.len()]);
+    Ok(())
+}
+
+impl SpiDevice<u8> for Connection {
+    fn transaction(&mut self, operations: &mut [spi::Operation<'_, u8>]) -> Result<()> {
+        let plan = Plan::new(operations);
+        let rx = Connection::transaction(self, &plan.segments())?;
+        Plan::finish(operations, rx)
+    }
+}
+
+impl SpiBus<u8> for Connection {
+    fn read(&mut self, words: &mut [u8]) -> Result<()> {
+        let data = Connection::read(self, words.len())?;
+        fill(words, &data, Operation::Read)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<()> {
+        Connection::write(self, words)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<()> {
+        let mut tx = write.to_vec();
+        tx.resize(read.len().max(write.len()), 0);
+        let data = Connection::transfer(self, &tx)?;
+        fill(read, &data, Operation::Transfer)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<()> {
+        let data = Connection::transfer(self, words)?;
+        fill(words, &data, Operation::Transfer)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream};
+
+    #[test]
+    fn device_operations_are_one_transaction() {
+        let mock = MockStream::new(vec![Expectation::transaction(vec![
+            Expectation::write(&[0x0b, 0x00]),
+            Expectation::write(&[]),
+            Expectation::read(&[0x12, 0x34]),
+            Expectation::transfer(&[0x56, 0x00], &[0x9a, 0xbc]),
+            Expectation::transfer(&[0x78], &[0xde]),
+        ])]);
+        let mut connection = Connection::new(Box::new(mock.clone()));
+        let mut read = [0u8; 2];
+        let mut transfer = [0u8; 2];
+        let mut in_place = [0x78];
+        SpiDevice::transaction(
+            &mut connection,
+            &mut [
+                spi::Operation::Write(&[0x0b, 0x00]),
+                spi::Operation::DelayNs(1500),
+                spi::Operation::Read(&mut read),
+                spi::Operation::Transfer(&mut transfer, &[0x56]),
+                spi::Operation::TransferInPlace(&mut in_place),
+            ],
+        )
+        .unwrap();
+        assert_eq!(read, [0x12, 0x34]);
+        assert_eq!(transfer, [0x9a, 0xbc]);
+        assert_eq!(in_place, [0xde]);
+        mock.done();
+    }
+
+    #[test]
+    fn long_delays_are_split() {
+        let plan = Plan::new(&[spi::Operation::DelayNs(200_000_000)]);
+        let delays: Vec<u16> = plan
+            .segments()
+            .iter()
+            .map(|segment| segment.delay_usecs)
+            .collect();
+        assert_eq!(delays, vec![u16::MAX, u16::MAX, u16::MAX, 3395]);
+    }
+
+    #[test]
+    fn bus_calls_are_single_operations() {
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x01]),
+            Expectation::read(&[0x02]),
+            Expectation::transfer(&[0x03, 0x00], &[0x04, 0x05]),
+            Expectation::transfer(&[0x06], &[0x07]),
+        ]);
+        let mut connection = Connection::new(Box::new(mock.clone()));
+        let mut read = [0u8];
+        let mut transfer = [0u8; 2];
+        let mut in_place = [0x06];
+        SpiBus::write(&mut connection, &[0x01]).unwrap();
+        SpiBus::read(&mut connection, &mut read).unwrap();
+        SpiBus::transfer(&mut connection, &mut transfer, &[0x03]).unwrap();
+        SpiBus::transfer_in_place(&mut connection, &mut in_place).unwrap();
+        assert_eq!(read, [0x02]);
+        assert_eq!(transfer, [0x04, 0x05]);
+        assert_eq!(in_place, [0x07]);
+        mock.done();
+    }
+
+    #[test]
+    fn short_data_is_a_length_mismatch() {
+        let mut buf = [0u8; 3];
+        assert!(matches!(
+            fill(&mut buf, &[1, 2], Operation::Read),
+            Err(Error::LengthMismatch {
+                expected: 3,
+                actual: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn protocol_errors_are_frame_format() {
+        let error = Error::Protocol("bad".to_string());
+        assert_eq!(spi::Error::kind(&error), ErrorKind::FrameFormat);
+        assert_eq!(spi::Error::kind(&Error::Unhealthy), ErrorKind::Other);
+    }
+}
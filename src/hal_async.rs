@@ -0,0 +1,69 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// embedded-hal-async SPI device trait for AsyncConnection
+//
+// The operation list is copied into one multi-segment transaction which runs
+// on the connection's worker thread, exactly like the blocking `SpiDevice`.
+
+use crate::hal::Plan;
+use crate::{AsyncConnection, Error, Result};
+use embedded_hal_async::spi::{ErrorType, Operation, SpiDevice};
+
+impl ErrorType for AsyncConnection {
+    type Error = Error;
+}
+
+impl SpiDevice<u8> for AsyncConnection {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<()> {
+        let plan = Plan::new(operations);
+        let rx = self
+            .run(move |stream| stream.transaction(&plan.segments()))
+            .await?;
+        Plan::finish(operations, rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream};
+
+    #[tokio::test]
+    async fn operations_are_one_transaction() {
+        let mock = MockStream::new(vec![Expectation::transaction(vec![
+            Expectation::write(&[0x03, 0x00]),
+            Expectation::read(&[0x12, 0x34]),
+            Expectation::transfer(&[0x56], &[0x78]),
+        ])]);
+        let mut connection = AsyncConnection::new(Box::new(mock.clone()));
+        let mut read = [0u8; 2];
+        let mut in_place = [0x56];
+        SpiDevice::transaction(
+            &mut connection,
+            &mut [
+                Operation::Write(&[0x03, 0x00]),
+                Operation::Read(&mut read),
+                Operation::TransferInPlace(&mut in_place),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(read, [0x12, 0x34]);
+        assert_eq!(in_place, [0x78]);
+        mock.done();
+    }
+}
@@ -16,9 +16,66 @@
 
 // A generalised HAL for communicating over SPI
 
-use std::io::Result;
+use std::io;
 use std::io::prelude::*;
-use spidev::{Spidev, SpidevOptions, SpidevTransfer, SpiModeFlags};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use spidev::{spidevioctl, Spidev, SpidevOptions, SpidevTransfer, SpiModeFlags};
+
+#[cfg(feature = "async")]
+mod async_connection;
+pub mod bitbang;
+pub mod clock;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+pub mod crc;
+pub mod data_ready;
+pub mod error;
+pub mod framing;
+pub mod gpio;
+#[cfg(feature = "embedded-hal")]
+mod hal;
+#[cfg(feature = "embedded-hal-async")]
+mod hal_async;
+pub mod mock;
+pub mod packet;
+pub mod register;
+pub mod register_map;
+pub mod remote;
+pub mod retry;
+pub mod segment;
+pub mod shared_bus;
+pub mod sim;
+pub mod timeout;
+mod worker;
+
+#[doc(hidden)]
+pub use paste;
+
+#[cfg(feature = "async")]
+pub use crate::async_connection::AsyncConnection;
+pub use crate::bitbang::BitBangStream;
+pub use crate::clock::{Clock, SystemClock, VirtualClock};
+pub use crate::crc::{Crc, CrcParams, CRC_16_CCITT, CRC_32, CRC_8};
+pub use crate::data_ready::{DataReady, Listener};
+pub use crate::error::{Error, Operation, Result};
+pub use crate::framing::FramedConnection;
+pub use crate::gpio::{
+    Edge, EdgeLine, GpioChipSelect, GpioCsStream, InputLine, OutputLine, Polarity,
+};
+pub use crate::mock::{Expectation, MockStream};
+pub use crate::packet::{PacketConfig, PacketTransport};
+pub use crate::register::{Endian, RegisterConfig, RegisterInterface};
+pub use crate::register_map::{Modifiable, Readable, Register, RegisterValue, Writable};
+pub use crate::remote::RemoteStream;
+pub use crate::retry::{Backoff, RetryPolicy, RetryStats, RetryingStream};
+pub use crate::segment::{Segment, SegmentKind};
+pub use crate::shared_bus::{BusDevice, ChipSelect, SharedBus};
+pub use crate::sim::eeprom::{Eeprom, EepromConfig, Fram, FramConfig};
+pub use crate::sim::nor_flash::{NorFlash, NorFlashConfig};
+pub use crate::sim::{SimDevice, SimulatedBus};
+pub use crate::timeout::{Health, TimeoutStream};
 
 /// High level read/write trait for SPI connections to implement
 pub trait Stream {
@@ -42,6 +99,155 @@ pub trait Stream {
     /// 
     /// `data` - Data to write
     fn transfer(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Perform several segments as one transaction with chip select held
+    ///
+    /// Returns the received data of every read and transfer segment, in order.
+    /// The default implementation clocks all segments as a single transfer and
+    /// rejects segments carrying per-transfer overrides.
+    ///
+    /// # Argument
+    ///
+    /// `segments` - Segments to perform, in order
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        if segments.iter().any(Segment::has_overrides) {
+            return Err(unsupported("per-segment transfer options"));
+        }
+        let mut tx = Vec::with_capacity(segments.iter().map(Segment::len).sum());
+        for segment in segments {
+            match segment.kind {
+                SegmentKind::Write(data) | SegmentKind::Transfer(data) => {
+                    tx.extend_from_slice(data)
+                }
+                SegmentKind::Read(len) => tx.resize(tx.len() + len, 0),
+            }
+        }
+        let mut rx = self.transfer(&tx)?.into_iter();
+        Ok(segments
+            .iter()
+            .map(|segment| rx.by_ref().take(segment.len()).collect::<Vec<u8>>())
+            .zip(segments)
+            .filter(|(_, segment)| segment.is_read())
+            .map(|(data, _)| data)
+            .collect())
+    }
+
+    /// Set the SPI mode of the device
+    ///
+    /// # Argument
+    ///
+    /// `mode` - SPI Mode
+    fn set_mode(&mut self, _mode: SpiModeFlags) -> Result<()> {
+        Err(unsupported("changing the SPI mode"))
+    }
+
+    /// Set the max clock speed of the device
+    ///
+    /// # Argument
+    ///
+    /// `max_speed` - Max speed in Hz
+    fn set_speed(&mut self, _max_speed: u32) -> Result<()> {
+        Err(unsupported("changing the clock speed"))
+    }
+
+    /// Set the bits per word of the device
+    ///
+    /// # Argument
+    ///
+    /// `bpw` - Bits per word
+    fn set_bits_per_word(&mut self, _bpw: u8) -> Result<()> {
+        Err(unsupported("changing the bits per word"))
+    }
+
+    /// Settings currently applied to the device
+    fn config(&self) -> Result<SpiConfig> {
+        Err(unsupported("querying the configuration"))
+    }
+
+    /// Hold the device for several operations in a row
+    ///
+    /// Streams shared with other users return a stream which keeps them out
+    /// until dropped. The default implementation returns `None`, meaning the
+    /// stream is not shared and borrowing it is already exclusive.
+    fn lock(&self) -> Result<Option<Box<dyn Stream + '_>>> {
+        Ok(None)
+    }
+
+    /// Time source of the device
+    ///
+    /// Simulated streams return their virtual clock, everything else the
+    /// wall clock, which the default implementation does.
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+}
+
+// Lets boxed streams, e.g. a `SpiStream` picked at runtime, be wrapped again
+impl<S: Stream + ?Sized> Stream for Box<S> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        (**self).write(data)
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        (**self).read(len)
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        (**self).transfer(data)
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        (**self).transaction(segments)
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        (**self).set_mode(mode)
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        (**self).set_speed(max_speed)
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        (**self).set_bits_per_word(bpw)
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        (**self).config()
+    }
+
+    fn lock(&self) -> Result<Option<Box<dyn Stream + '_>>> {
+        (**self).lock()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        (**self).clock()
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::Unsupported(what.to_string())
+}
+
+/// Settings of a SPI device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiConfig {
+    /// Bits per word
+    pub bits_per_word: u8,
+    /// Max speed in Hz
+    pub max_speed_hz: u32,
+    /// SPI Mode
+    pub mode: SpiModeFlags,
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            bits_per_word: 8,
+            max_speed_hz: 0,
+            mode: SpiModeFlags::SPI_MODE_0,
+        }
+    }
 }
 
 /// Struct for communicating with an SPI device
@@ -100,49 +306,470 @@ impl Connection {
     pub fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
         self.stream.transfer(data)
     }
+
+    /// Perform several segments as one transaction with chip select held
+    ///
+    /// Returns the received data of every read and transfer segment, in order
+    ///
+    /// # Argument
+    ///
+    /// `segments` - Segments to perform, in order
+    pub fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        self.stream.transaction(segments)
+    }
+
+    /// Set the SPI mode of the device
+    ///
+    /// # Argument
+    ///
+    /// `mode` - SPI Mode
+    pub fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.stream.set_mode(mode)
+    }
+
+    /// Set the max clock speed of the device
+    ///
+    /// # Argument
+    ///
+    /// `max_speed` - Max speed in Hz
+    pub fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.stream.set_speed(max_speed)
+    }
+
+    /// Set the bits per word of the device
+    ///
+    /// # Argument
+    ///
+    /// `bpw` - Bits per word
+    pub fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.stream.set_bits_per_word(bpw)
+    }
+
+    /// Settings the device is actually using, as reported back by the driver
+    pub fn config(&self) -> Result<SpiConfig> {
+        self.stream.config()
+    }
+
+    /// Run several operations without other users of the bus interleaving
+    ///
+    /// # Argument
+    ///
+    /// `f` - Operations to run against the held stream
+    pub fn exclusive<R>(&mut self, f: impl FnOnce(&mut dyn Stream) -> Result<R>) -> Result<R> {
+        if let Some(mut stream) = self.stream.lock()? {
+            return f(stream.as_mut());
+        }
+        f(self.stream.as_mut())
+    }
+
+    /// Issue a status command until the status satisfies `done`
+    ///
+    /// Returns the status which satisfied `done`. Fails with
+    /// `Error::StatusTimeout`, carrying the last status read, once `timeout`
+    /// has passed on the stream's clock.
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command reading the status
+    /// `status_len` - Length of the status in bytes
+    /// `interval` - Delay between attempts
+    /// `timeout` - Time after which to give up
+    /// `done` - Decides whether the status is the awaited one
+    pub fn poll_until(
+        &self,
+        command: &[u8],
+        status_len: usize,
+        interval: Duration,
+        timeout: Duration,
+        mut done: impl FnMut(&[u8]) -> bool,
+    ) -> Result<Vec<u8>> {
+        let clock = self.stream.clock();
+        let deadline = clock.now() + timeout;
+        loop {
+            let status = self
+                .transaction(&[Segment::write(command), Segment::read(status_len)])?
+                .pop()
+                .unwrap_or_default();
+            if done(&status) {
+                return Ok(status);
+            }
+            let now = clock.now();
+            if now >= deadline {
+                return Err(Error::StatusTimeout { status });
+            }
+            clock.sleep(interval.min(deadline - now));
+        }
+    }
+
+    /// Issue a status command until the masked status byte equals `value`
+    ///
+    /// e.g. `poll_until_masked(&[0x05], 0x01, 0x00, ...)` waits for a flash
+    /// chip's busy bit to clear.
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command reading the status byte
+    /// `mask` - Bits of the status to compare
+    /// `value` - Awaited value of the masked bits
+    /// `interval` - Delay between attempts
+    /// `timeout` - Time after which to give up
+    pub fn poll_until_masked(
+        &self,
+        command: &[u8],
+        mask: u8,
+        value: u8,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<u8> {
+        let status = self.poll_until(command, 1, interval, timeout, |status| {
+            status.first().is_some_and(|status| status & mask == value)
+        })?;
+        Ok(status[0])
+    }
 }
 
+/// Stream for a SPIDEV device
 pub struct SpiStream {
     spidev: spidev::Spidev,
+    path: String,
 }
 impl SpiStream {
-    fn new(
+    /// Open and configure a SPIDEV device
+    ///
+    /// Use this over `Connection::from_path` to wrap the stream first, e.g. in
+    /// a `SharedBus` or a `RetryingStream`.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to SPI device
+    /// `bpw` - Bits per word
+    /// `max_speed` - Max speed in Hz
+    /// `mode` - SPI Mode
+    pub fn new(
         path: String,
         bpw: u8,
         max_speed: u32,
         mode: SpiModeFlags,
     ) -> Result<Self> {
-        let mut spi = Spidev::open(path)?;
-        let options = SpidevOptions::new()
-            .bits_per_word(bpw)
-            .max_speed_hz(max_speed)
-            .mode(mode)
-            .build();
-        spi.configure(&options)?;
-        Ok(SpiStream{
+        let spi = Spidev::open(&path).map_err(|source| Error::Open {
+            path: path.clone(),
+            source,
+        })?;
+        let mut stream = SpiStream {
             spidev: spi,
-        })
+            path,
+        };
+        stream.configure(
+            &SpidevOptions::new()
+                .bits_per_word(bpw)
+                .max_speed_hz(max_speed)
+                .mode(mode)
+                .build(),
+        )?;
+        Ok(stream)
+    }
+
+    fn configure(&mut self, options: &SpidevOptions) -> Result<()> {
+        self.spidev
+            .configure(options)
+            .map_err(|source| Error::Configure {
+                path: self.path.clone(),
+                source,
+            })
+    }
+
+    // Attach the device path and operation to an ioctl failure
+    fn failed(&self, op: Operation) -> impl Fn(io::Error) -> Error + '_ {
+        move |source| Error::Transfer {
+            path: self.path.clone(),
+            op,
+            source,
+        }
     }
 }
 // Read and write implementations for the SpiStream
 impl Stream for SpiStream {
     fn write(&mut self, data: &[u8]) -> Result<()> {
-        match self.spidev.write(data) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+        let written = self
+            .spidev
+            .write(data)
+            .map_err(self.failed(Operation::Write))?;
+        if written != data.len() {
+            return Err(Error::LengthMismatch {
+                op: Operation::Write,
+                expected: data.len(),
+                actual: written,
+            });
         }
+        Ok(())
     }
 
     fn read(&mut self, len: usize) -> Result<Vec<u8>> {
         let mut buf = vec![0u8;len];
-        self.spidev.read(&mut buf)?;
+        let read = self
+            .spidev
+            .read(&mut buf)
+            .map_err(self.failed(Operation::Read))?;
+        if read != len {
+            return Err(Error::LengthMismatch {
+                op: Operation::Read,
+                expected: len,
+                actual: read,
+            });
+        }
         Ok(buf)
     }
 
     fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
         let mut buf = vec![0u8;data.len()];
         let mut transfer = SpidevTransfer::read_write(data, &mut buf);
-        self.spidev.transfer(&mut transfer)?;
+        self.spidev
+            .transfer(&mut transfer)
+            .map_err(self.failed(Operation::Transfer))?;
         Ok(buf)
     }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        let mut bufs: Vec<Vec<u8>> = segments
+            .iter()
+            .map(|segment| match segment.kind {
+                SegmentKind::Write(_) => Vec::new(),
+                _ => vec![0u8; segment.len()],
+            })
+            .collect();
+        let mut transfers: Vec<SpidevTransfer> = segments
+            .iter()
+            .zip(bufs.iter_mut())
+            .map(|(segment, buf)| {
+                let mut transfer = match segment.kind {
+                    SegmentKind::Write(data) => SpidevTransfer::write(data),
+                    SegmentKind::Read(_) => SpidevTransfer::read(buf),
+                    SegmentKind::Transfer(data) => SpidevTransfer::read_write(data, buf),
+                };
+                transfer.cs_change = segment.cs_change as u8;
+                transfer.delay_usecs = segment.delay_usecs;
+                transfer.speed_hz = segment.speed_hz;
+                transfer.bits_per_word = segment.bits_per_word;
+                transfer
+            })
+            .collect();
+        self.spidev
+            .transfer_multiple(&mut transfers)
+            .map_err(self.failed(Operation::Transaction))?;
+        drop(transfers);
+        Ok(bufs
+            .into_iter()
+            .zip(segments)
+            .filter(|(_, segment)| segment.is_read())
+            .map(|(buf, _)| buf)
+            .collect())
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.configure(&SpidevOptions::new().mode(mode).build())
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.configure(&SpidevOptions::new().max_speed_hz(max_speed).build())
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.configure(&SpidevOptions::new().bits_per_word(bpw).build())
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        let fd = self.spidev.inner().as_raw_fd();
+        let failed = |source| Error::Configure {
+            path: self.path.clone(),
+            source,
+        };
+        let mode = spidevioctl::get_mode(fd).map_err(failed)?;
+        let mut mode = SpiModeFlags::from_bits_truncate(u32::from(mode));
+        mode.set(
+            SpiModeFlags::SPI_LSB_FIRST,
+            spidevioctl::get_lsb_first(fd).map_err(failed)? != 0,
+        );
+        Ok(SpiConfig {
+            bits_per_word: spidevioctl::get_bits_per_word(fd).map_err(failed)?,
+            max_speed_hz: spidevioctl::get_max_speed_hz(fd).map_err(failed)?,
+            mode,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stream implementing only the required methods, to exercise the defaults
+    struct TransferOnly(MockStream);
+
+    impl Stream for TransferOnly {
+        fn write(&mut self, data: &[u8]) -> Result<()> {
+            self.0.write(data)
+        }
+
+        fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+            self.0.read(len)
+        }
+
+        fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+            self.0.transfer(data)
+        }
+    }
+
+    // Stream answering from a MockStream with time on a VirtualClock
+    struct Clocked(MockStream, Arc<VirtualClock>);
+
+    impl Stream for Clocked {
+        fn write(&mut self, data: &[u8]) -> Result<()> {
+            self.0.write(data)
+        }
+
+        fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+            self.0.read(len)
+        }
+
+        fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+            self.0.transfer(data)
+        }
+
+        fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+            self.0.transaction(segments)
+        }
+
+        fn clock(&self) -> Arc<dyn Clock> {
+            self.1.clone()
+        }
+    }
+
+    fn status_read(command: &[u8], status: &[u8]) -> Expectation {
+        Expectation::transaction(vec![Expectation::write(command), Expectation::read(status)])
+    }
+
+    fn clocked(statuses: &[&[u8]]) -> (Connection, MockStream, Arc<VirtualClock>) {
+        let mock = MockStream::new(statuses.iter().map(|status| status_read(&[0x05], status)).collect());
+        let clock = Arc::new(VirtualClock::new());
+        let connection = Connection::new(Box::new(Clocked(mock.clone(), clock.clone())));
+        (connection, mock, clock)
+    }
+
+    #[test]
+    fn default_transaction_is_one_transfer() {
+        let mock = MockStream::new(vec![Expectation::transfer(
+            &[0x03, 0x00, 0x10, 0x00, 0x00, 0xaa],
+            &[0xff, 0xff, 0xff, 0x12, 0x34, 0x56],
+        )]);
+        let connection = Connection::new(Box::new(TransferOnly(mock.clone())));
+        let rx = connection
+            .transaction(&[
+                Segment::write(&[0x03, 0x00, 0x10]),
+                Segment::read(2),
+                Segment::transfer(&[0xaa]),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0x12, 0x34], vec![0x56]]);
+        mock.done();
+    }
+
+    #[test]
+    fn transaction_is_forwarded() {
+        let mock = MockStream::new(vec![Expectation::transaction(vec![
+            Expectation::write(&[0x9f]),
+            Expectation::read(&[0xef, 0x40, 0x18]),
+        ])]);
+        let connection = Connection::new(Box::new(mock.clone()));
+        let rx = connection
+            .transaction(&[Segment::write(&[0x9f]), Segment::read(3)])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0xef, 0x40, 0x18]]);
+        mock.done();
+    }
+
+    #[test]
+    fn default_transaction_rejects_overrides() {
+        let mock = MockStream::new(vec![]);
+        let connection = Connection::new(Box::new(TransferOnly(mock.clone())));
+        for segment in &[
+            Segment::write(&[0x01]).cs_change(true),
+            Segment::delay(10),
+            Segment::read(1).speed_hz(1_000_000),
+            Segment::read(1).bits_per_word(16),
+        ] {
+            assert!(matches!(
+                connection.transaction(&[*segment]),
+                Err(Error::Unsupported(_))
+            ));
+        }
+        mock.done();
+    }
+
+    #[test]
+    fn settings_are_forwarded() {
+        let mock = MockStream::new(vec![]);
+        let mut connection = Connection::new(Box::new(mock));
+        connection.set_mode(SpiModeFlags::SPI_MODE_2).unwrap();
+        connection.set_speed(500_000).unwrap();
+        connection.set_bits_per_word(12).unwrap();
+        assert_eq!(
+            connection.config().unwrap(),
+            SpiConfig {
+                bits_per_word: 12,
+                max_speed_hz: 500_000,
+                mode: SpiModeFlags::SPI_MODE_2,
+            }
+        );
+    }
+
+    #[test]
+    fn poll_until_returns_the_accepted_status() {
+        let (connection, mock, clock) = clocked(&[&[0x00, 0x01], &[0x80, 0x02]]);
+        let status = connection
+            .poll_until(&[0x05], 2, Duration::from_millis(10), Duration::from_secs(1), |status| {
+                status[0] & 0x80 != 0
+            })
+            .unwrap();
+        assert_eq!(status, vec![0x80, 0x02]);
+        assert_eq!(clock.elapsed(), Duration::from_millis(10));
+        mock.done();
+    }
+
+    #[test]
+    fn poll_until_masked_compares_the_masked_bits() {
+        let (connection, mock, clock) = clocked(&[&[0x08], &[0x0d], &[0x05]]);
+        let status = connection
+            .poll_until_masked(&[0x05], 0x0c, 0x04, Duration::from_millis(10), Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(status, 0x05);
+        assert_eq!(clock.elapsed(), Duration::from_millis(20));
+        mock.done();
+    }
+
+    #[test]
+    fn polling_times_out_with_the_last_status() {
+        let (connection, mock, clock) = clocked(&[&[0x01], &[0x01], &[0x03]]);
+        let result = connection.poll_until_masked(
+            &[0x05],
+            0x01,
+            0x00,
+            Duration::from_millis(30),
+            Duration::from_millis(50),
+        );
+        match result {
+            Err(Error::StatusTimeout { status }) => assert_eq!(status, vec![0x03]),
+            other => panic!("expected a status timeout, got {:?}", other),
+        }
+        // The second sleep is cut short at the deadline
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+        mock.done();
+    }
+
+    #[test]
+    fn default_settings_are_unsupported() {
+        let mut connection = Connection::new(Box::new(TransferOnly(MockStream::new(vec![]))));
+        assert!(matches!(connection.set_mode(SpiModeFlags::SPI_MODE_1), Err(Error::Unsupported(_))));
+        assert!(matches!(connection.set_speed(1), Err(Error::Unsupported(_))));
+        assert!(matches!(connection.set_bits_per_word(8), Err(Error::Unsupported(_))));
+        assert!(matches!(connection.config(), Err(Error::Unsupported(_))));
+    }
 }
@@ -0,0 +1,354 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Scriptable Stream for testing drivers without SPI hardware
+
+use crate::{Error, Result, Segment, SegmentKind, SpiConfig, Stream};
+use spidev::SpiModeFlags;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// A single operation a `MockStream` expects to be issued
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    /// Expect a write of exactly these bytes
+    Write(Vec<u8>),
+    /// Expect a read, answering with these bytes
+    Read(Vec<u8>),
+    /// Expect a transfer of `tx`, answering with `rx`
+    Transfer { tx: Vec<u8>, rx: Vec<u8> },
+    /// Expect a transaction whose segments match these expectations
+    Transaction(Vec<Expectation>),
+}
+
+impl Expectation {
+    /// Expect a write of `data`
+    pub fn write(data: &[u8]) -> Self {
+        Expectation::Write(data.to_vec())
+    }
+
+    /// Expect a read of `data.len()` bytes, answering with `data`
+    pub fn read(data: &[u8]) -> Self {
+        Expectation::Read(data.to_vec())
+    }
+
+    /// Expect a transfer of `tx`, answering with `rx`
+    pub fn transfer(tx: &[u8], rx: &[u8]) -> Self {
+        Expectation::Transfer {
+            tx: tx.to_vec(),
+            rx: rx.to_vec(),
+        }
+    }
+
+    /// Expect a transaction made of `segments`
+    pub fn transaction(segments: Vec<Expectation>) -> Self {
+        Expectation::Transaction(segments)
+    }
+
+    // Scripted response if `segment` matches this expectation
+    fn respond(&self, segment: &Segment) -> Option<Option<Vec<u8>>> {
+        match (self, segment.kind) {
+            (Expectation::Write(expected), SegmentKind::Write(data))
+                if expected.as_slice() == data =>
+            {
+                Some(None)
+            }
+            (Expectation::Read(rx), SegmentKind::Read(len)) if rx.len() == len => {
+                Some(Some(rx.clone()))
+            }
+            (Expectation::Transfer { tx, rx }, SegmentKind::Transfer(data))
+                if tx.as_slice() == data && rx.len() == tx.len() =>
+            {
+                Some(Some(rx.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+struct State {
+    expected: VecDeque<Expectation>,
+    config: SpiConfig,
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if !thread::panicking() && !self.expected.is_empty() {
+            panic!(
+                "MockStream dropped with unconsumed expectations: {:?}",
+                self.expected
+            );
+        }
+    }
+}
+
+/// Stream which replays a scripted queue of expected operations
+///
+/// Clones share the same queue, so a clone can be handed to
+/// `Connection::new` while the original is kept to call `done()`.
+/// Dropping the last clone with expectations left over panics.
+/// Configuration changes are accepted and reported back by `config()`.
+#[derive(Clone)]
+pub struct MockStream {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockStream {
+    /// MockStream constructor
+    ///
+    /// # Argument
+    ///
+    /// `expectations` - Operations expected, in order
+    pub fn new(expectations: Vec<Expectation>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                expected: expectations.into(),
+                config: SpiConfig::default(),
+            })),
+        }
+    }
+
+    /// Append an expectation to the end of the queue
+    ///
+    /// # Argument
+    ///
+    /// `expectation` - Operation expected
+    pub fn expect(&self, expectation: Expectation) {
+        self.state().expected.push_back(expectation);
+    }
+
+    /// Assert that every expectation has been consumed
+    ///
+    /// Panics listing the remaining expectations otherwise
+    pub fn done(&self) {
+        let remaining: Vec<Expectation> = self.state().expected.drain(..).collect();
+        if !remaining.is_empty() {
+            panic!("MockStream has unconsumed expectations: {:?}", remaining);
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next(&self, issued: &str) -> Result<Expectation> {
+        self.state()
+            .expected
+            .pop_front()
+            .ok_or_else(|| mismatch(format!("unexpected {}, no expectations left", issued)))
+    }
+}
+
+fn mismatch(msg: String) -> Error {
+    Error::Protocol(format!("MockStream: {}", msg))
+}
+
+impl Stream for MockStream {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let issued = format!("write {:02x?}", data);
+        match self.next(&issued)? {
+            Expectation::Write(ref expected) if expected.as_slice() == data => Ok(()),
+            other => Err(mismatch(format!("expected {:02x?}, got {}", other, issued))),
+        }
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        let issued = format!("read of {} bytes", len);
+        match self.next(&issued)? {
+            Expectation::Read(data) if data.len() == len => Ok(data),
+            other => Err(mismatch(format!("expected {:02x?}, got {}", other, issued))),
+        }
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let issued = format!("transfer {:02x?}", data);
+        match self.next(&issued)? {
+            Expectation::Transfer { ref tx, ref rx } if tx.as_slice() == data => {
+                if rx.len() != tx.len() {
+                    return Err(mismatch(format!(
+                        "scripted rx {:02x?} is not the length of tx {:02x?}",
+                        rx, tx
+                    )));
+                }
+                Ok(rx.clone())
+            }
+            other => Err(mismatch(format!("expected {:02x?}, got {}", other, issued))),
+        }
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        let issued = format!("transaction {:02x?}", segments);
+        let expected = match self.next(&issued)? {
+            Expectation::Transaction(expected) if expected.len() == segments.len() => expected,
+            other => return Err(mismatch(format!("expected {:02x?}, got {}", other, issued))),
+        };
+        let mut bufs = Vec::new();
+        for (expectation, segment) in expected.iter().zip(segments) {
+            match expectation.respond(segment) {
+                Some(Some(rx)) => bufs.push(rx),
+                Some(None) => (),
+                None => {
+                    return Err(mismatch(format!(
+                        "expected segment {:02x?}, got {:02x?}",
+                        expectation, segment
+                    )))
+                }
+            }
+        }
+        Ok(bufs)
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.state().config.mode = mode;
+        Ok(())
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.state().config.max_speed_hz = max_speed;
+        Ok(())
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.state().config.bits_per_word = bpw;
+        Ok(())
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        Ok(self.state().config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_expectations_in_order() {
+        let mut mock = MockStream::new(vec![
+            Expectation::write(&[0x01, 0x02]),
+            Expectation::read(&[0xaa]),
+            Expectation::transfer(&[0x03], &[0xbb]),
+        ]);
+        mock.write(&[0x01, 0x02]).unwrap();
+        assert_eq!(mock.read(1).unwrap(), vec![0xaa]);
+        assert_eq!(mock.transfer(&[0x03]).unwrap(), vec![0xbb]);
+        mock.done();
+    }
+
+    #[test]
+    fn mismatches_are_protocol_errors() {
+        let mut mock = MockStream::new(vec![
+            Expectation::write(&[0x01]),
+            Expectation::read(&[0xaa, 0xbb]),
+            Expectation::transfer(&[0x03], &[0xcc]),
+            Expectation::write(&[0x04]),
+        ]);
+        assert!(matches!(mock.write(&[0x02]), Err(Error::Protocol(_))));
+        assert!(matches!(mock.read(1), Err(Error::Protocol(_))));
+        assert!(matches!(mock.transfer(&[0x04]), Err(Error::Protocol(_))));
+        assert!(matches!(mock.read(1), Err(Error::Protocol(_))));
+        assert!(matches!(mock.write(&[0x05]), Err(Error::Protocol(_))));
+        mock.done();
+    }
+
+    #[test]
+    fn scripted_transfer_length_is_checked() {
+        let mock = MockStream::new(vec![Expectation::transfer(&[0x01, 0x02], &[0xaa])]);
+        assert!(matches!(
+            mock.transfer(&[0x01, 0x02]),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn clones_share_the_queue() {
+        let mock = MockStream::new(vec![]);
+        let mut clone = mock.clone();
+        mock.expect(Expectation::write(&[0x42]));
+        clone.write(&[0x42]).unwrap();
+        mock.done();
+    }
+
+    #[test]
+    #[should_panic(expected = "unconsumed expectations")]
+    fn done_panics_with_expectations_left() {
+        let mock = MockStream::new(vec![Expectation::write(&[0x01])]);
+        mock.done();
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped with unconsumed expectations")]
+    fn drop_panics_with_expectations_left() {
+        let mock = MockStream::new(vec![Expectation::read(&[0x01])]);
+        drop(mock.clone());
+        drop(mock);
+    }
+
+    #[test]
+    fn transaction_segments_match() {
+        let mock = MockStream::new(vec![Expectation::transaction(vec![
+            Expectation::write(&[0x9f]),
+            Expectation::read(&[0xef, 0x40, 0x18]),
+            Expectation::transfer(&[0x00], &[0x55]),
+        ])]);
+        let rx = mock
+            .transaction(&[
+                Segment::write(&[0x9f]),
+                Segment::read(3),
+                Segment::transfer(&[0x00]),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0xef, 0x40, 0x18], vec![0x55]]);
+        mock.done();
+    }
+
+    #[test]
+    fn transaction_mismatches_are_protocol_errors() {
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![Expectation::write(&[0x9f]), Expectation::read(&[0])]),
+            Expectation::transaction(vec![Expectation::write(&[0x9f]), Expectation::read(&[0])]),
+            Expectation::write(&[0x9f]),
+        ]);
+        let wrong_data = [Segment::write(&[0x9e]), Segment::read(1)];
+        assert!(matches!(
+            mock.transaction(&wrong_data),
+            Err(Error::Protocol(_))
+        ));
+        let wrong_count = [Segment::write(&[0x9f])];
+        assert!(matches!(
+            mock.transaction(&wrong_count),
+            Err(Error::Protocol(_))
+        ));
+        assert!(matches!(
+            mock.transaction(&wrong_count),
+            Err(Error::Protocol(_))
+        ));
+        mock.done();
+    }
+
+    #[test]
+    fn config_changes_are_reported() {
+        let mut mock = MockStream::new(vec![]);
+        mock.set_mode(SpiModeFlags::SPI_MODE_3).unwrap();
+        mock.set_speed(1_000_000).unwrap();
+        mock.set_bits_per_word(16).unwrap();
+        let config = mock.config().unwrap();
+        assert_eq!(config.mode, SpiModeFlags::SPI_MODE_3);
+        assert_eq!(config.max_speed_hz, 1_000_000);
+        assert_eq!(config.bits_per_word, 16);
+    }
+}
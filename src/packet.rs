@@ -0,0 +1,469 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Packet exchange with slaves which answer after an unknown delay
+//
+// A packet is the sync marker, the payload length and the payload, optionally
+// followed by a CRC over length and payload:
+//
+//     | sync | length | payload | crc |
+//
+// The master sends its request as one packet, then clocks filler bytes until
+// the slave's response packet shows up, giving up after a number of polls.
+
+use crate::crc::Crc;
+use crate::{Connection, Endian, Error, Operation, Result};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Packet format and polling behaviour
+#[derive(Clone)]
+pub struct PacketConfig {
+    /// Marker starting every packet
+    pub sync: Vec<u8>,
+    /// Width of the length prefix in bytes, 1 to 4
+    pub length_bytes: usize,
+    /// Byte order of the length prefix and CRC
+    pub endian: Endian,
+    /// Largest payload accepted in either direction
+    pub max_payload: usize,
+    /// Byte clocked out while polling for a response
+    pub filler: u8,
+    /// Bytes clocked per poll
+    pub poll_bytes: usize,
+    /// Polls made before giving up on a response
+    pub poll_limit: usize,
+    /// Delay between polls
+    pub poll_interval: Duration,
+    /// CRC appended to packets, if any
+    pub crc: Option<Crc>,
+}
+
+impl Default for PacketConfig {
+    fn default() -> Self {
+        Self {
+            sync: vec![0xaa, 0x55],
+            length_bytes: 2,
+            endian: Endian::Big,
+            max_payload: 256,
+            filler: 0xff,
+            poll_bytes: 1,
+            poll_limit: 1000,
+            poll_interval: Duration::from_secs(0),
+            crc: None,
+        }
+    }
+}
+
+impl PacketConfig {
+    /// Set the sync marker
+    ///
+    /// # Argument
+    ///
+    /// `sync` - Marker starting every packet
+    pub fn sync(mut self, sync: &[u8]) -> Self {
+        self.sync = sync.to_vec();
+        self
+    }
+
+    /// Set the length prefix format
+    ///
+    /// # Arguments
+    ///
+    /// `bytes` - Width of the length prefix, 1 to 4
+    /// `endian` - Byte order of the length prefix and CRC
+    pub fn length_prefix(mut self, bytes: usize, endian: Endian) -> Self {
+        self.length_bytes = bytes;
+        self.endian = endian;
+        self
+    }
+
+    /// Set the largest payload
+    ///
+    /// # Argument
+    ///
+    /// `max_payload` - Largest payload accepted in either direction
+    pub fn max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// Set the filler byte
+    ///
+    /// # Argument
+    ///
+    /// `filler` - Byte clocked out while polling
+    pub fn filler(mut self, filler: u8) -> Self {
+        self.filler = filler;
+        self
+    }
+
+    /// Set how the slave is polled for a response
+    ///
+    /// # Arguments
+    ///
+    /// `bytes` - Bytes clocked per poll
+    /// `limit` - Polls made before giving up
+    /// `interval` - Delay between polls
+    pub fn polling(mut self, bytes: usize, limit: usize, interval: Duration) -> Self {
+        self.poll_bytes = bytes;
+        self.poll_limit = limit;
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Protect packets with a CRC
+    ///
+    /// # Argument
+    ///
+    /// `crc` - CRC over length prefix and payload
+    pub fn crc(mut self, crc: Crc) -> Self {
+        self.crc = Some(crc);
+        self
+    }
+
+    fn length_bytes(&self) -> usize {
+        self.length_bytes.clamp(1, 4)
+    }
+
+    // Low `bytes` bytes of `value` in the configured order
+    fn encode(&self, value: u32, bytes: usize) -> Vec<u8> {
+        let be = value.to_be_bytes();
+        let mut out = be[be.len() - bytes..].to_vec();
+        if self.endian == Endian::Little {
+            out.reverse();
+        }
+        out
+    }
+
+    fn decode(&self, bytes: &[u8]) -> u32 {
+        let fold = |value: u32, &byte: &u8| (value << 8) | u32::from(byte);
+        match self.endian {
+            Endian::Big => bytes.iter().fold(0, fold),
+            Endian::Little => bytes.iter().rev().fold(0, fold),
+        }
+    }
+}
+
+/// Request/response packet exchange over a `Connection`
+pub struct PacketTransport {
+    connection: Connection,
+    config: PacketConfig,
+}
+
+impl PacketTransport {
+    /// PacketTransport constructor
+    ///
+    /// # Arguments
+    ///
+    /// `connection` - Connection to the slave
+    /// `config` - Packet format and polling behaviour
+    pub fn new(connection: Connection, config: PacketConfig) -> Self {
+        Self { connection, config }
+    }
+
+    /// Packet format in use
+    pub fn config(&self) -> &PacketConfig {
+        &self.config
+    }
+
+    /// Underlying connection
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Give back the underlying connection
+    pub fn into_inner(self) -> Connection {
+        self.connection
+    }
+
+    /// Send a request packet
+    ///
+    /// # Argument
+    ///
+    /// `payload` - Payload of the request
+    pub fn send_request(&self, payload: &[u8]) -> Result<()> {
+        let config = &self.config;
+        let max = config
+            .max_payload
+            .min((u32::MAX >> (32 - 8 * config.length_bytes())) as usize);
+        if payload.len() > max {
+            return Err(Error::Protocol(format!(
+                "request of {} bytes exceeds the maximum of {}",
+                payload.len(),
+                max
+            )));
+        }
+        let mut packet = config.sync.clone();
+        let body = packet.len();
+        packet.extend(config.encode(payload.len() as u32, config.length_bytes()));
+        packet.extend_from_slice(payload);
+        if let Some(crc) = &config.crc {
+            let value = crc.checksum(&packet[body..]);
+            packet.extend(config.encode(value, crc.bytes()));
+        }
+        self.connection.transfer(&packet)?;
+        Ok(())
+    }
+
+    /// Poll for the response packet and return its payload
+    ///
+    /// Fails with `Error::Timeout` when no sync marker shows up within the
+    /// poll limit, and with `Error::Checksum` when the CRC does not match.
+    pub fn receive_response(&self) -> Result<Vec<u8>> {
+        let config = &self.config;
+        let mut incoming = Incoming {
+            transport: self,
+            buffer: VecDeque::new(),
+            polls: 0,
+        };
+        incoming.sync()?;
+        let length = incoming.take(config.length_bytes())?;
+        let len = config.decode(&length) as usize;
+        if len > config.max_payload {
+            return Err(Error::Protocol(format!(
+                "response of {} bytes exceeds the maximum of {}",
+                len, config.max_payload
+            )));
+        }
+        let crc_bytes = config.crc.as_ref().map_or(0, Crc::bytes);
+        let mut payload = incoming.take(len + crc_bytes)?;
+        if let Some(crc) = &config.crc {
+            let received = config.decode(&payload.split_off(len));
+            let mut body = length;
+            body.extend_from_slice(&payload);
+            let computed = crc.checksum(&body);
+            if received != computed {
+                return Err(Error::Checksum {
+                    expected: computed,
+                    actual: received,
+                });
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Send a request and wait for its response
+    ///
+    /// # Argument
+    ///
+    /// `payload` - Payload of the request
+    pub fn request(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        self.send_request(payload)?;
+        self.receive_response()
+    }
+}
+
+// For each prefix of `sync`, the length of its longest proper prefix which is
+// also a suffix, so a partial match can fall back without dropping bytes when
+// the marker overlaps itself, as with aa aa 55
+fn fallback_table(sync: &[u8]) -> Vec<usize> {
+    let mut table = vec![0; sync.len()];
+    let mut len = 0;
+    for i in 1..sync.len() {
+        while len > 0 && sync[i] != sync[len] {
+            len = table[len - 1];
+        }
+        if sync[i] == sync[len] {
+            len += 1;
+        }
+        table[i] = len;
+    }
+    table
+}
+
+// Bytes clocked in from the slave, fetched with filler transfers as needed
+struct Incoming<'a> {
+    transport: &'a PacketTransport,
+    buffer: VecDeque<u8>,
+    polls: usize,
+}
+
+impl Incoming<'_> {
+    // Poll until the sync marker has been received
+    fn sync(&mut self) -> Result<()> {
+        let sync = &self.transport.config.sync;
+        let fallback = fallback_table(sync);
+        let mut matched = 0;
+        while matched < sync.len() {
+            if self.buffer.is_empty() {
+                self.poll()?;
+            }
+            while let Some(byte) = self.buffer.pop_front() {
+                while matched > 0 && byte != sync[matched] {
+                    matched = fallback[matched - 1];
+                }
+                if byte == sync[matched] {
+                    matched += 1;
+                }
+                if matched == sync.len() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        let config = &self.transport.config;
+        if self.polls >= config.poll_limit {
+            return Err(Error::Timeout {
+                op: Operation::Read,
+            });
+        }
+        if self.polls > 0 {
+            self.transport
+                .connection
+                .stream
+                .clock()
+                .sleep(config.poll_interval);
+        }
+        self.polls += 1;
+        self.fill(config.poll_bytes.max(1))
+    }
+
+    // Clock in `len` more bytes
+    fn fill(&mut self, len: usize) -> Result<()> {
+        let filler = vec![self.transport.config.filler; len];
+        let rx = self.transport.connection.transfer(&filler)?;
+        if rx.len() != len {
+            return Err(Error::LengthMismatch {
+                op: Operation::Transfer,
+                expected: len,
+                actual: rx.len(),
+            });
+        }
+        self.buffer.extend(rx);
+        Ok(())
+    }
+
+    // Next `len` bytes of the packet
+    fn take(&mut self, len: usize) -> Result<Vec<u8>> {
+        if self.buffer.len() < len {
+            self.fill(len - self.buffer.len())?;
+        }
+        Ok(self.buffer.drain(..len).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream, CRC_16_CCITT};
+
+    fn transport(mock: &MockStream, config: PacketConfig) -> PacketTransport {
+        PacketTransport::new(Connection::new(Box::new(mock.clone())), config)
+    }
+
+    #[test]
+    fn request_and_response() {
+        let mock = MockStream::new(vec![
+            Expectation::transfer(&[0xaa, 0x55, 0x00, 0x02, 0x10, 0x20], &[0xff; 6]),
+            Expectation::transfer(&[0xff; 4], &[0xff, 0xff, 0xaa, 0x55]),
+            // Once synchronised, only the bytes still missing are clocked in
+            Expectation::transfer(&[0xff; 2], &[0x00, 0x01]),
+            Expectation::transfer(&[0xff], &[0x42]),
+        ]);
+        let config = PacketConfig::default().polling(4, 10, Duration::from_secs(0));
+        let response = transport(&mock, config).request(&[0x10, 0x20]).unwrap();
+        assert_eq!(response, vec![0x42]);
+        mock.done();
+    }
+
+    #[test]
+    fn self_overlapping_sync() {
+        let mock = MockStream::new(vec![Expectation::transfer(
+            &[0xff; 7],
+            &[0xaa, 0xaa, 0xaa, 0x55, 0x00, 0x01, 0x42],
+        )]);
+        let config =
+            PacketConfig::default()
+                .sync(&[0xaa, 0xaa, 0x55])
+                .polling(7, 1, Duration::from_secs(0));
+        assert_eq!(
+            transport(&mock, config).receive_response().unwrap(),
+            vec![0x42]
+        );
+        mock.done();
+    }
+
+    #[test]
+    fn fallback_table_of_markers() {
+        assert_eq!(fallback_table(&[0xaa, 0xaa, 0x55]), [0, 1, 0]);
+        assert_eq!(fallback_table(&[0xaa, 0x55, 0xaa, 0x55]), [0, 0, 1, 2]);
+        assert!(fallback_table(&[]).is_empty());
+    }
+
+    #[test]
+    fn no_sync_times_out() {
+        let mock = MockStream::new(vec![
+            Expectation::transfer(&[0xff], &[0xff]),
+            Expectation::transfer(&[0xff], &[0xaa]),
+        ]);
+        let config = PacketConfig::default().polling(1, 2, Duration::from_secs(0));
+        assert!(matches!(
+            transport(&mock, config).receive_response(),
+            Err(Error::Timeout {
+                op: Operation::Read
+            })
+        ));
+        mock.done();
+    }
+
+    #[test]
+    fn crc_is_checked() {
+        let crc = Crc::new(CRC_16_CCITT);
+        let value = crc.checksum(&[0x00, 0x01, 0x42]).to_be_bytes();
+        let good = [0xaa, 0x55, 0x00, 0x01, 0x42, value[2], value[3]];
+        let mut corrupted = good;
+        corrupted[4] = 0x43;
+        let mock = MockStream::new(vec![
+            Expectation::transfer(&[0xff; 7], &good),
+            Expectation::transfer(&[0xff; 7], &corrupted),
+        ]);
+        let config = PacketConfig::default()
+            .polling(7, 1, Duration::from_secs(0))
+            .crc(crc);
+        let transport = transport(&mock, config);
+        assert_eq!(transport.receive_response().unwrap(), vec![0x42]);
+        assert!(matches!(
+            transport.receive_response(),
+            Err(Error::Checksum { .. })
+        ));
+        mock.done();
+    }
+
+    #[test]
+    fn oversized_packets_are_rejected() {
+        let mock = MockStream::new(vec![Expectation::transfer(
+            &[0xff; 4],
+            &[0xaa, 0x55, 0x01, 0x00],
+        )]);
+        let config = PacketConfig::default()
+            .max_payload(16)
+            .polling(4, 1, Duration::from_secs(0));
+        let transport = transport(&mock, config);
+        assert!(matches!(
+            transport.send_request(&[0; 17]),
+            Err(Error::Protocol(_))
+        ));
+        assert!(matches!(
+            transport.receive_response(),
+            Err(Error::Protocol(_))
+        ));
+        mock.done();
+    }
+}
@@ -0,0 +1,395 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Register access for devices with an address/data command format
+
+use crate::register_map::{Modifiable, Readable, RegisterValue, Writable};
+use crate::{Connection, Error, Operation, Result, Segment, Stream};
+
+/// Byte order of multi-byte addresses and register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    /// Most significant byte first
+    Big,
+    /// Least significant byte first
+    Little,
+}
+
+/// Addressing convention of a device's register interface
+///
+/// The default matches the common sensor convention: one address byte, bit 7
+/// set for reads, no auto-increment flag, no dummy bytes, big endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterConfig {
+    /// Number of address bytes sent, 1 to 4
+    pub address_bytes: usize,
+    /// Mask of the read/write flag within the address
+    pub rw_flag: u32,
+    /// Whether the read/write flag is set for reads, rather than for writes
+    pub read_sets_flag: bool,
+    /// Mask of the flag enabling auto-increment for multi-register accesses
+    pub increment_flag: u32,
+    /// Dummy bytes clocked between address and data on reads
+    pub dummy_bytes: usize,
+    /// Byte order of multi-byte addresses and register values
+    pub endian: Endian,
+}
+
+impl Default for RegisterConfig {
+    fn default() -> Self {
+        Self {
+            address_bytes: 1,
+            rw_flag: 0x80,
+            read_sets_flag: true,
+            increment_flag: 0,
+            dummy_bytes: 0,
+            endian: Endian::Big,
+        }
+    }
+}
+
+impl RegisterConfig {
+    /// Set the number of address bytes
+    ///
+    /// # Argument
+    ///
+    /// `bytes` - Address width in bytes, 1 to 4
+    pub fn address_bytes(mut self, bytes: usize) -> Self {
+        self.address_bytes = bytes;
+        self
+    }
+
+    /// Set the read/write flag and its polarity
+    ///
+    /// # Arguments
+    ///
+    /// `mask` - Mask of the flag within the address
+    /// `read_sets_flag` - Whether the flag is set for reads, rather than for writes
+    pub fn rw_flag(mut self, mask: u32, read_sets_flag: bool) -> Self {
+        self.rw_flag = mask;
+        self.read_sets_flag = read_sets_flag;
+        self
+    }
+
+    /// Set the auto-increment flag
+    ///
+    /// # Argument
+    ///
+    /// `mask` - Mask of the flag within the address, 0 for none
+    pub fn increment_flag(mut self, mask: u32) -> Self {
+        self.increment_flag = mask;
+        self
+    }
+
+    /// Set the number of dummy bytes on reads
+    ///
+    /// # Argument
+    ///
+    /// `bytes` - Dummy bytes between address and data
+    pub fn dummy_bytes(mut self, bytes: usize) -> Self {
+        self.dummy_bytes = bytes;
+        self
+    }
+
+    /// Set the byte order
+    ///
+    /// # Argument
+    ///
+    /// `endian` - Byte order of addresses and values
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    // Address bytes, with flags, starting an access of `len` registers
+    fn header(&self, address: u32, read: bool, len: usize) -> Vec<u8> {
+        let mut word = address;
+        if read == self.read_sets_flag {
+            word |= self.rw_flag;
+        } else {
+            word &= !self.rw_flag;
+        }
+        if len > 1 {
+            word |= self.increment_flag;
+        }
+        let bytes = word.to_be_bytes();
+        let width = self.address_bytes.clamp(1, bytes.len());
+        let mut header = bytes[bytes.len() - width..].to_vec();
+        if self.endian == Endian::Little {
+            header.reverse();
+        }
+        if read {
+            header.resize(header.len() + self.dummy_bytes, 0);
+        }
+        header
+    }
+
+    pub(crate) fn read_regs(
+        &self,
+        stream: &dyn Stream,
+        address: u32,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let header = self.header(address, true, len);
+        let mut rx = stream.transaction(&[Segment::write(&header), Segment::read(len)])?;
+        let data = rx.pop().unwrap_or_default();
+        if data.len() != len {
+            return Err(Error::LengthMismatch {
+                op: Operation::Read,
+                expected: len,
+                actual: data.len(),
+            });
+        }
+        Ok(data)
+    }
+
+    pub(crate) fn write_regs(&self, stream: &dyn Stream, address: u32, data: &[u8]) -> Result<()> {
+        let header = self.header(address, false, data.len());
+        stream.transaction(&[Segment::write(&header), Segment::write(data)])?;
+        Ok(())
+    }
+}
+
+/// Register level access to a SPI device
+pub struct RegisterInterface {
+    connection: Connection,
+    config: RegisterConfig,
+}
+
+impl RegisterInterface {
+    /// RegisterInterface constructor
+    ///
+    /// # Arguments
+    ///
+    /// `connection` - Connection to the device
+    /// `config` - Addressing convention of the device
+    pub fn new(connection: Connection, config: RegisterConfig) -> Self {
+        Self { connection, config }
+    }
+
+    /// Addressing convention in use
+    pub fn config(&self) -> &RegisterConfig {
+        &self.config
+    }
+
+    /// Underlying connection
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Give back the underlying connection
+    pub fn into_inner(self) -> Connection {
+        self.connection
+    }
+
+    /// Read a single register
+    ///
+    /// # Argument
+    ///
+    /// `address` - Register address
+    pub fn read_reg(&self, address: u32) -> Result<u8> {
+        Ok(self.read_regs(address, 1)?[0])
+    }
+
+    /// Write a single register
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Register address
+    /// `value` - Value to write
+    pub fn write_reg(&self, address: u32, value: u8) -> Result<()> {
+        self.write_regs(address, &[value])
+    }
+
+    /// Read consecutive registers in one access
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Address of the first register
+    /// `len` - Number of registers to read
+    pub fn read_regs(&self, address: u32, len: usize) -> Result<Vec<u8>> {
+        self.config
+            .read_regs(self.connection.stream.as_ref(), address, len)
+    }
+
+    /// Write consecutive registers in one access
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Address of the first register
+    /// `data` - Values to write
+    pub fn write_regs(&self, address: u32, data: &[u8]) -> Result<()> {
+        self.config
+            .write_regs(self.connection.stream.as_ref(), address, data)
+    }
+
+    /// Read a register, change it with `f` and write it back
+    ///
+    /// The bus is held for both accesses. Returns the value written.
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Register address
+    /// `f` - Computes the new value from the current one
+    pub fn modify_reg(&mut self, address: u32, f: impl FnOnce(u8) -> u8) -> Result<u8> {
+        let config = self.config;
+        self.connection.exclusive(|stream| {
+            let data = config.read_regs(stream, address, 1)?;
+            let value = f(data[0]);
+            config.write_regs(stream, address, &[value])?;
+            Ok(value)
+        })
+    }
+
+    /// Read a typed register
+    pub fn read<R: Readable>(&self) -> Result<R> {
+        let data = self.read_regs(R::ADDRESS, R::Raw::BYTES)?;
+        Ok(R::from_raw(R::Raw::from_bytes(&data, self.config.endian)))
+    }
+
+    /// Write a typed register
+    ///
+    /// # Argument
+    ///
+    /// `value` - Register value to write
+    pub fn write<R: Writable>(&self, value: R) -> Result<()> {
+        self.write_regs(R::ADDRESS, &value.raw().to_bytes(self.config.endian))
+    }
+
+    /// Read a typed register, change it with `f` and write it back
+    ///
+    /// The bus is held for both accesses. Returns the value written.
+    ///
+    /// # Argument
+    ///
+    /// `f` - Changes the register value
+    pub fn modify<R: Modifiable>(&mut self, f: impl FnOnce(&mut R)) -> Result<R> {
+        let config = self.config;
+        self.connection.exclusive(|stream| {
+            let data = config.read_regs(stream, R::ADDRESS, R::Raw::BYTES)?;
+            let mut value = R::from_raw(R::Raw::from_bytes(&data, config.endian));
+            f(&mut value);
+            config.write_regs(stream, R::ADDRESS, &value.raw().to_bytes(config.endian))?;
+            Ok(value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream};
+
+    fn interface(config: RegisterConfig, mock: &MockStream) -> RegisterInterface {
+        RegisterInterface::new(Connection::new(Box::new(mock.clone())), config)
+    }
+
+    #[test]
+    fn default_header() {
+        let config = RegisterConfig::default();
+        assert_eq!(config.header(0x0f, true, 1), [0x8f]);
+        assert_eq!(config.header(0x8f, false, 1), [0x0f]);
+    }
+
+    #[test]
+    fn configured_header() {
+        let config = RegisterConfig::default()
+            .address_bytes(2)
+            .rw_flag(0x01, false)
+            .increment_flag(0x4000)
+            .dummy_bytes(1)
+            .endian(Endian::Little);
+        assert_eq!(config.header(0x1234, true, 1), [0x34, 0x12, 0x00]);
+        assert_eq!(config.header(0x1234, false, 2), [0x35, 0x52]);
+    }
+
+    #[test]
+    fn register_accesses() {
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![
+                Expectation::write(&[0x8f]),
+                Expectation::read(&[0x33]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x20]),
+                Expectation::write(&[0x47]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0xa8]),
+                Expectation::read(&[0x01, 0x02, 0x03]),
+            ]),
+        ]);
+        let registers = interface(RegisterConfig::default(), &mock);
+        assert_eq!(registers.read_reg(0x0f).unwrap(), 0x33);
+        registers.write_reg(0x20, 0x47).unwrap();
+        assert_eq!(
+            registers.read_regs(0x28, 3).unwrap(),
+            vec![0x01, 0x02, 0x03]
+        );
+        mock.done();
+    }
+
+    #[test]
+    fn modify_reg_writes_back() {
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![
+                Expectation::write(&[0xa0]),
+                Expectation::read(&[0x07]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x20]),
+                Expectation::write(&[0x87]),
+            ]),
+        ]);
+        let mut registers = interface(RegisterConfig::default(), &mock);
+        assert_eq!(
+            registers.modify_reg(0x20, |value| value | 0x80).unwrap(),
+            0x87
+        );
+        mock.done();
+    }
+
+    #[test]
+    fn short_reads_are_length_mismatches() {
+        // A stream answering a register read with fewer bytes than asked for
+        struct Short;
+
+        impl Stream for Short {
+            fn write(&mut self, _data: &[u8]) -> Result<()> {
+                Ok(())
+            }
+
+            fn read(&mut self, _len: usize) -> Result<Vec<u8>> {
+                Ok(Vec::new())
+            }
+
+            fn transfer(&self, _data: &[u8]) -> Result<Vec<u8>> {
+                Ok(Vec::new())
+            }
+        }
+
+        let registers =
+            RegisterInterface::new(Connection::new(Box::new(Short)), RegisterConfig::default());
+        assert!(matches!(
+            registers.read_regs(0x00, 2),
+            Err(Error::LengthMismatch {
+                expected: 2,
+                actual: 0,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,350 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Typed registers generated from a declarative register map
+//
+// `register_map!` turns a device description into one struct per register:
+//
+//     register_map! {
+//         /// Device identification
+//         pub WhoAmI @ 0x0f: u8 = 0x33, RO {}
+//         /// Control register 1
+//         pub Ctrl1 @ 0x20: u8 = 0x07, RW {
+//             /// Output data rate
+//             odr: 4..=7,
+//             /// Low power mode
+//             lpen: 3,
+//         }
+//     }
+//
+// Single bit fields get `bool` accessors, bit ranges get accessors of the
+// register's value type, named `field()` and `set_field()`. Access is one of
+// RO, WO, RW or W1C. Only readable registers implement `Readable` and only
+// writable ones `Writable`, so writing a read-only register does not compile.
+// `modify` is limited to RW registers, as writing back a W1C register would
+// clear every flag that was read as set.
+
+use crate::{Endian, RegisterInterface, Result};
+
+/// Value type of a register
+pub trait RegisterValue: Copy {
+    /// Width of the value in bytes
+    const BYTES: usize;
+
+    /// Encode the value
+    ///
+    /// # Argument
+    ///
+    /// `endian` - Byte order
+    fn to_bytes(self, endian: Endian) -> Vec<u8>;
+
+    /// Decode a value of `BYTES` bytes
+    ///
+    /// # Arguments
+    ///
+    /// `bytes` - Encoded value
+    /// `endian` - Byte order
+    fn from_bytes(bytes: &[u8], endian: Endian) -> Self;
+}
+
+macro_rules! register_value {
+    ($($raw:ty),*) => {
+        $(
+            impl RegisterValue for $raw {
+                const BYTES: usize = std::mem::size_of::<$raw>();
+
+                fn to_bytes(self, endian: Endian) -> Vec<u8> {
+                    match endian {
+                        Endian::Big => self.to_be_bytes().to_vec(),
+                        Endian::Little => self.to_le_bytes().to_vec(),
+                    }
+                }
+
+                fn from_bytes(bytes: &[u8], endian: Endian) -> Self {
+                    let mut buf = [0u8; std::mem::size_of::<$raw>()];
+                    buf.copy_from_slice(&bytes[..Self::BYTES]);
+                    match endian {
+                        Endian::Big => <$raw>::from_be_bytes(buf),
+                        Endian::Little => <$raw>::from_le_bytes(buf),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+register_value!(u8, u16, u32);
+
+/// Register of a device, usually generated by `register_map!`
+pub trait Register: Copy {
+    /// Value type of the register
+    type Raw: RegisterValue;
+    /// Address of the register
+    const ADDRESS: u32;
+    /// Value of the register after reset
+    const RESET: Self::Raw;
+
+    /// Wrap a raw value
+    fn from_raw(raw: Self::Raw) -> Self;
+
+    /// Raw value of the register
+    fn raw(self) -> Self::Raw;
+}
+
+/// Register which can be read
+pub trait Readable: Register {
+    /// Read the register from the device
+    ///
+    /// # Argument
+    ///
+    /// `regs` - Register interface of the device
+    fn read(regs: &RegisterInterface) -> Result<Self> {
+        regs.read::<Self>()
+    }
+}
+
+/// Register which can be written
+pub trait Writable: Register {
+    /// Write the register to the device
+    ///
+    /// # Argument
+    ///
+    /// `regs` - Register interface of the device
+    fn write(self, regs: &RegisterInterface) -> Result<()> {
+        regs.write(self)
+    }
+}
+
+/// Register which can be read, changed and written back
+pub trait Modifiable: Readable + Writable {
+    /// Read the register, change it with `f` and write it back
+    ///
+    /// # Arguments
+    ///
+    /// `regs` - Register interface of the device
+    /// `f` - Changes the register value
+    fn modify(regs: &mut RegisterInterface, f: impl FnOnce(&mut Self)) -> Result<Self> {
+        regs.modify(f)
+    }
+}
+
+/// Generate typed registers from a register map
+///
+/// Read-only registers do not implement `Writable`, so writing one does not
+/// compile:
+///
+/// ```compile_fail
+/// use spi_rs::{register_map, RegisterInterface, Result};
+///
+/// register_map! {
+///     pub WhoAmI @ 0x0f: u8 = 0x33, RO {}
+/// }
+///
+/// fn overwrite(regs: &RegisterInterface, value: WhoAmI) -> Result<()> {
+///     regs.write(value)
+/// }
+/// ```
+#[macro_export]
+macro_rules! register_map {
+    ($(
+        $(#[$meta:meta])*
+        $vis:vis $name:ident @ $address:literal : $raw:ty = $reset:expr, $access:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field:ident : $lo:literal $(..= $hi:literal)?
+            ),* $(,)?
+        }
+    )*) => {$(
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        $vis struct $name(pub $raw);
+
+        impl $crate::register_map::Register for $name {
+            type Raw = $raw;
+            const ADDRESS: u32 = $address;
+            const RESET: $raw = $reset;
+
+            fn from_raw(raw: $raw) -> Self {
+                $name(raw)
+            }
+
+            fn raw(self) -> $raw {
+                self.0
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name($reset)
+            }
+        }
+
+        impl $name {
+            $(
+                $crate::register_map!(@field $raw, [$(#[$field_meta])*], $field, $lo $(, $hi)?);
+            )*
+        }
+
+        $crate::register_map!(@access $name, $access);
+    )*};
+
+    (@field $raw:ty, [$(#[$meta:meta])*], $field:ident, $bit:literal) => {
+        $crate::paste::paste! {
+            $(#[$meta])*
+            pub fn $field(&self) -> bool {
+                (self.0 >> $bit) & 1 != 0
+            }
+
+            $(#[$meta])*
+            pub fn [<set_ $field>](&mut self, value: bool) -> &mut Self {
+                self.0 = (self.0 & !(1 << $bit)) | ((value as $raw) << $bit);
+                self
+            }
+        }
+    };
+
+    (@field $raw:ty, [$(#[$meta:meta])*], $field:ident, $lo:literal, $hi:literal) => {
+        $crate::paste::paste! {
+            $(#[$meta])*
+            pub fn $field(&self) -> $raw {
+                (self.0 >> $lo) & (<$raw>::MAX >> (<$raw>::BITS - ($hi - $lo + 1)))
+            }
+
+            $(#[$meta])*
+            pub fn [<set_ $field>](&mut self, value: $raw) -> &mut Self {
+                let mask = (<$raw>::MAX >> (<$raw>::BITS - ($hi - $lo + 1))) << $lo;
+                self.0 = (self.0 & !mask) | ((value << $lo) & mask);
+                self
+            }
+        }
+    };
+
+    (@access $name:ident, RO) => {
+        impl $crate::register_map::Readable for $name {}
+    };
+
+    (@access $name:ident, WO) => {
+        impl $crate::register_map::Writable for $name {}
+    };
+
+    (@access $name:ident, RW) => {
+        impl $crate::register_map::Readable for $name {}
+        impl $crate::register_map::Writable for $name {}
+        impl $crate::register_map::Modifiable for $name {}
+    };
+
+    (@access $name:ident, W1C) => {
+        impl $crate::register_map::Readable for $name {}
+        impl $crate::register_map::Writable for $name {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Expectation, MockStream, RegisterConfig};
+
+    crate::register_map! {
+        WhoAmI @ 0x0f: u8 = 0x33, RO {}
+        Ctrl1 @ 0x20: u8 = 0x07, RW {
+            odr: 4..=7,
+            lpen: 3,
+            axes: 0..=2,
+        }
+        Threshold @ 0x32: u16 = 0x0100, WO {
+            value: 0..=15,
+        }
+        IntSource @ 0x31: u8 = 0x00, W1C {
+            active: 6,
+        }
+    }
+
+    fn interface(mock: &MockStream) -> RegisterInterface {
+        RegisterInterface::new(
+            Connection::new(Box::new(mock.clone())),
+            RegisterConfig::default(),
+        )
+    }
+
+    #[test]
+    fn fields() {
+        let mut ctrl = Ctrl1::default();
+        assert_eq!(ctrl, Ctrl1(Ctrl1::RESET));
+        assert_eq!(ctrl.axes(), 0x07);
+        assert!(!ctrl.lpen());
+        ctrl.set_odr(0x5).set_lpen(true).set_axes(0x9);
+        assert_eq!(ctrl.0, 0x59);
+        assert_eq!(ctrl.odr(), 0x5);
+        assert!(ctrl.lpen());
+        let mut threshold = Threshold::default();
+        threshold.set_value(0xbeef);
+        assert_eq!(threshold.value(), 0xbeef);
+        assert_eq!(Threshold::ADDRESS, 0x32);
+        assert_eq!(IntSource::default().set_active(true).0, 0x40);
+    }
+
+    #[test]
+    fn values_follow_endian() {
+        assert_eq!(0x1234u16.to_bytes(Endian::Big), [0x12, 0x34]);
+        assert_eq!(0x1234u16.to_bytes(Endian::Little), [0x34, 0x12]);
+        assert_eq!(
+            u32::from_bytes(&[0x78, 0x56, 0x34, 0x12], Endian::Little),
+            0x1234_5678
+        );
+    }
+
+    #[test]
+    fn typed_accesses() {
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![
+                Expectation::write(&[0x8f]),
+                Expectation::read(&[0x33]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x32]),
+                Expectation::write(&[0x12, 0x34]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0xa0]),
+                Expectation::read(&[0x07]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x20]),
+                Expectation::write(&[0x0f]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0xb1]),
+                Expectation::read(&[0x40]),
+            ]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x31]),
+                Expectation::write(&[0x40]),
+            ]),
+        ]);
+        let mut regs = interface(&mock);
+        assert_eq!(WhoAmI::read(&regs).unwrap(), WhoAmI(0x33));
+        Threshold(0x1234).write(&regs).unwrap();
+        let ctrl = Ctrl1::modify(&mut regs, |ctrl| {
+            ctrl.set_lpen(true);
+        })
+        .unwrap();
+        assert_eq!(ctrl, Ctrl1(0x0f));
+        let source = IntSource::read(&regs).unwrap();
+        assert!(source.active());
+        source.write(&regs).unwrap();
+        mock.done();
+    }
+}
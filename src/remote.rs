@@ -0,0 +1,828 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// SPI access over TCP, for running drivers away from the bus
+//
+// The client sends requests and the server answers each with one response.
+// Both are framed as a tag byte, a 32 bit body length and the body:
+//
+//     | tag: u8 | len: u32 | body: len bytes |
+//
+// All integers are big endian. Request tags and bodies:
+//
+//     0x01 write              data
+//     0x02 read               len: u32
+//     0x03 transfer           data
+//     0x04 transaction        count: u32, then per segment:
+//                               kind: u8 (0 write, 1 read, 2 transfer),
+//                               cs_change: u8, delay_usecs: u16,
+//                               speed_hz: u32, bits_per_word: u8,
+//                               len: u32, data (write and transfer only)
+//     0x05 set mode           mode flags: u32
+//     0x06 set speed          max_speed_hz: u32
+//     0x07 set bits per word  bits_per_word: u8
+//     0x08 config             empty
+//
+// Responses carry tag 0x00 on success, with the received data for read and
+// transfer, `count: u32` followed by `len: u32, data` per read segment for
+// transaction, and `bits_per_word: u8, max_speed_hz: u32, mode: u32` for
+// config. Other bodies are empty. Failures carry tag 0x01 and the `Error`
+// variant, followed by its fields:
+//
+//     0 open, 1 configure     path, io error
+//     2 transfer              path, op: u8, io error
+//     3 length mismatch       op: u8, expected: u32, actual: u32
+//     4 timeout               op: u8
+//     5 status timeout        status
+//     6 unhealthy             nothing
+//     7 checksum              expected: u32, actual: u32
+//     8 unsupported           message
+//     9 protocol              message
+//     10 I/O                  io error
+//
+// Paths, messages and statuses are prefixed with their length as a u32, `op`
+// indexes `OPERATIONS` and an io error is `kind: u8, os_error: i32, message`,
+// `kind` indexing `ERROR_KINDS`. An `os_error` other than 0 is the errno,
+// which the error is rebuilt from.
+//
+// Bodies are limited to `MAX_BODY` bytes either way. Requests whose body or
+// response would exceed it, reads included, fail with `Error::Protocol`
+// before anything is sent, and the server rejects them the same way.
+//
+// The server serves one client at a time, which therefore has the bus to
+// itself.
+
+use crate::{Connection, Error, Operation, Result, Segment, SegmentKind, SpiConfig, Stream};
+use spidev::SpiModeFlags;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const WRITE: u8 = 0x01;
+const READ: u8 = 0x02;
+const TRANSFER: u8 = 0x03;
+const TRANSACTION: u8 = 0x04;
+const SET_MODE: u8 = 0x05;
+const SET_SPEED: u8 = 0x06;
+const SET_BITS_PER_WORD: u8 = 0x07;
+const CONFIG: u8 = 0x08;
+
+const OK: u8 = 0x00;
+const FAILED: u8 = 0x01;
+
+// Largest body accepted, guarding against corrupt length fields
+const MAX_BODY: u32 = 16 << 20;
+
+/// Error kinds preserved across the connection, by index
+pub const ERROR_KINDS: [io::ErrorKind; 9] = [
+    io::ErrorKind::Other,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::Unsupported,
+    io::ErrorKind::BrokenPipe,
+];
+
+/// Operations preserved across the connection, by index
+pub const OPERATIONS: [Operation; 6] = [
+    Operation::Open,
+    Operation::Configure,
+    Operation::Write,
+    Operation::Read,
+    Operation::Transfer,
+    Operation::Transaction,
+];
+
+// Fail unless a body of `len` bytes fits in a frame
+fn check_body(len: usize) -> Result<()> {
+    if len > MAX_BODY as usize {
+        return Err(Error::Protocol(format!(
+            "body of {} bytes exceeds the maximum of {}",
+            len, MAX_BODY
+        )));
+    }
+    Ok(())
+}
+
+// Length of the response body to a transaction of `segments`
+fn transaction_response_len(segments: &[Segment]) -> usize {
+    let data: usize = segments
+        .iter()
+        .filter(|segment| segment.is_read())
+        .map(|segment| 4 + segment.len())
+        .sum();
+    4 + data
+}
+
+fn send(socket: &mut impl Write, tag: u8, body: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(5 + body.len());
+    frame.push(tag);
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    socket.write_all(&frame)
+}
+
+// Next frame, `None` if the peer closed the connection in between frames
+fn receive(socket: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match socket.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => socket.read_exact(&mut header[1..])?,
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    if len > MAX_BODY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the maximum", len),
+        ));
+    }
+    let mut body = vec![0u8; len as usize];
+    socket.read_exact(&mut body)?;
+    Ok(Some((header[0], body)))
+}
+
+// Cursor over a message body
+struct Body<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::Protocol("truncated message".to_string()));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        self.bytes(self.data.len()).unwrap_or_default()
+    }
+
+    // Bytes prefixed with their length
+    fn prefixed(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.prefixed()?).into_owned())
+    }
+
+    fn operation(&mut self) -> Result<Operation> {
+        let op = self.u8()?;
+        OPERATIONS
+            .get(op as usize)
+            .copied()
+            .ok_or_else(|| Error::Protocol(format!("unknown operation {}", op)))
+    }
+
+    fn io_error(&mut self) -> Result<io::Error> {
+        let kind = ERROR_KINDS
+            .get(self.u8()? as usize)
+            .copied()
+            .unwrap_or(io::ErrorKind::Other);
+        let os_error = self.u32()? as i32;
+        let message = self.string()?;
+        if os_error != 0 {
+            return Ok(io::Error::from_raw_os_error(os_error));
+        }
+        Ok(io::Error::new(kind, message))
+    }
+}
+
+fn put_prefixed(body: &mut Vec<u8>, data: &[u8]) {
+    body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    body.extend_from_slice(data);
+}
+
+fn put_operation(body: &mut Vec<u8>, op: Operation) {
+    let op = OPERATIONS
+        .iter()
+        .position(|&known| known == op)
+        .unwrap_or(0);
+    body.push(op as u8);
+}
+
+fn put_io_error(body: &mut Vec<u8>, error: &io::Error) {
+    let kind = ERROR_KINDS
+        .iter()
+        .position(|&kind| kind == error.kind())
+        .unwrap_or(0);
+    body.push(kind as u8);
+    body.extend_from_slice(&error.raw_os_error().unwrap_or(0).to_be_bytes());
+    put_prefixed(body, error.to_string().as_bytes());
+}
+
+fn put_len(body: &mut Vec<u8>, len: usize) {
+    body.extend_from_slice(&(len.min(u32::MAX as usize) as u32).to_be_bytes());
+}
+
+fn encode_error(error: &Error) -> Vec<u8> {
+    let mut body = Vec::new();
+    match error {
+        Error::Open { path, source } => {
+            body.push(0);
+            put_prefixed(&mut body, path.as_bytes());
+            put_io_error(&mut body, source);
+        }
+        Error::Configure { path, source } => {
+            body.push(1);
+            put_prefixed(&mut body, path.as_bytes());
+            put_io_error(&mut body, source);
+        }
+        Error::Transfer { path, op, source } => {
+            body.push(2);
+            put_prefixed(&mut body, path.as_bytes());
+            put_operation(&mut body, *op);
+            put_io_error(&mut body, source);
+        }
+        Error::LengthMismatch {
+            op,
+            expected,
+            actual,
+        } => {
+            body.push(3);
+            put_operation(&mut body, *op);
+            put_len(&mut body, *expected);
+            put_len(&mut body, *actual);
+        }
+        Error::Timeout { op } => {
+            body.push(4);
+            put_operation(&mut body, *op);
+        }
+        Error::StatusTimeout { status } => {
+            body.push(5);
+            put_prefixed(&mut body, status);
+        }
+        Error::Unhealthy => body.push(6),
+        Error::Checksum { expected, actual } => {
+            body.push(7);
+            body.extend_from_slice(&expected.to_be_bytes());
+            body.extend_from_slice(&actual.to_be_bytes());
+        }
+        Error::Unsupported(what) => {
+            body.push(8);
+            put_prefixed(&mut body, what.as_bytes());
+        }
+        Error::Protocol(msg) => {
+            body.push(9);
+            put_prefixed(&mut body, msg.as_bytes());
+        }
+        Error::Io(source) => {
+            body.push(10);
+            put_io_error(&mut body, source);
+        }
+    }
+    body
+}
+
+// Error carried by a failure response, or the reason it is malformed
+fn decode_error(body: &[u8]) -> Error {
+    let mut body = Body { data: body };
+    let error = match body.u8() {
+        Ok(0) => body.string().and_then(|path| {
+            let source = body.io_error()?;
+            Ok(Error::Open { path, source })
+        }),
+        Ok(1) => body.string().and_then(|path| {
+            let source = body.io_error()?;
+            Ok(Error::Configure { path, source })
+        }),
+        Ok(2) => body.string().and_then(|path| {
+            let op = body.operation()?;
+            let source = body.io_error()?;
+            Ok(Error::Transfer { path, op, source })
+        }),
+        Ok(3) => body.operation().and_then(|op| {
+            Ok(Error::LengthMismatch {
+                op,
+                expected: body.u32()? as usize,
+                actual: body.u32()? as usize,
+            })
+        }),
+        Ok(4) => body.operation().map(|op| Error::Timeout { op }),
+        Ok(5) => body.prefixed().map(|status| Error::StatusTimeout {
+            status: status.to_vec(),
+        }),
+        Ok(6) => Ok(Error::Unhealthy),
+        Ok(7) => body.u32().and_then(|expected| {
+            Ok(Error::Checksum {
+                expected,
+                actual: body.u32()?,
+            })
+        }),
+        Ok(8) => body.string().map(Error::Unsupported),
+        Ok(9) => body.string().map(Error::Protocol),
+        Ok(10) => body.io_error().map(Error::Io),
+        Ok(variant) => Err(Error::Protocol(format!(
+            "unknown error variant {}",
+            variant
+        ))),
+        Err(e) => Err(e),
+    };
+    error.unwrap_or_else(|e| e)
+}
+
+// Fail unless `rx` holds the `expected` number of bytes
+fn check_len(op: Operation, expected: usize, rx: Vec<u8>) -> Result<Vec<u8>> {
+    if rx.len() != expected {
+        return Err(Error::LengthMismatch {
+            op,
+            expected,
+            actual: rx.len(),
+        });
+    }
+    Ok(rx)
+}
+
+fn encode_config(config: &SpiConfig) -> Vec<u8> {
+    let mut body = vec![config.bits_per_word];
+    body.extend_from_slice(&config.max_speed_hz.to_be_bytes());
+    body.extend_from_slice(&config.mode.bits().to_be_bytes());
+    body
+}
+
+/// Stream for a SPI device exposed by a remote server
+pub struct RemoteStream {
+    socket: TcpStream,
+    address: String,
+}
+
+impl RemoteStream {
+    /// Connect to a server
+    ///
+    /// # Argument
+    ///
+    /// `address` - Address the server listens on
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self> {
+        let socket = TcpStream::connect(address).map_err(Error::Io)?;
+        socket.set_nodelay(true).map_err(Error::Io)?;
+        let address = socket
+            .peer_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        Ok(Self { socket, address })
+    }
+
+    /// Address of the server
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    // Send a request and wait for its response
+    fn call(&self, tag: u8, body: &[u8]) -> Result<Vec<u8>> {
+        check_body(body.len())?;
+        let mut socket = &self.socket;
+        send(&mut socket, tag, body)?;
+        match receive(&mut socket)? {
+            Some((OK, body)) => Ok(body),
+            Some((FAILED, body)) => Err(decode_error(&body)),
+            Some((tag, _)) => Err(Error::Protocol(format!(
+                "unknown response tag {:#04x} from {}",
+                tag, self.address
+            ))),
+            None => Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} closed the connection", self.address),
+            ))),
+        }
+    }
+}
+
+impl Stream for RemoteStream {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.call(WRITE, data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        check_body(len)?;
+        let rx = self.call(READ, &(len as u32).to_be_bytes())?;
+        check_len(Operation::Read, len, rx)
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let rx = self.call(TRANSFER, data)?;
+        check_len(Operation::Transfer, data.len(), rx)
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        check_body(transaction_response_len(segments))?;
+        let mut request = (segments.len() as u32).to_be_bytes().to_vec();
+        for segment in segments {
+            let (kind, data): (u8, &[u8]) = match segment.kind {
+                SegmentKind::Write(data) => (0, data),
+                SegmentKind::Read(_) => (1, &[]),
+                SegmentKind::Transfer(data) => (2, data),
+            };
+            request.push(kind);
+            request.push(segment.cs_change as u8);
+            request.extend_from_slice(&segment.delay_usecs.to_be_bytes());
+            request.extend_from_slice(&segment.speed_hz.to_be_bytes());
+            request.push(segment.bits_per_word);
+            request.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            request.extend_from_slice(data);
+        }
+        let response = self.call(TRANSACTION, &request)?;
+        let mut body = Body { data: &response };
+        (0..body.u32()?)
+            .map(|_| {
+                let len = body.u32()? as usize;
+                Ok(body.bytes(len)?.to_vec())
+            })
+            .collect()
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.call(SET_MODE, &mode.bits().to_be_bytes())?;
+        Ok(())
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.call(SET_SPEED, &max_speed.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.call(SET_BITS_PER_WORD, &[bpw])?;
+        Ok(())
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        let response = self.call(CONFIG, &[])?;
+        let mut body = Body { data: &response };
+        Ok(SpiConfig {
+            bits_per_word: body.u8()?,
+            max_speed_hz: body.u32()?,
+            mode: SpiModeFlags::from_bits_truncate(body.u32()?),
+        })
+    }
+}
+
+/// Serve a connection to clients, one at a time
+///
+/// Only returns if accepting a client fails. Clients which send malformed
+/// frames or drop their connection are disconnected.
+///
+/// # Arguments
+///
+/// `connection` - Connection to expose
+/// `listener` - Socket to accept clients on
+pub fn serve(connection: &mut Connection, listener: &TcpListener) -> io::Result<()> {
+    loop {
+        let (client, _) = listener.accept()?;
+        let _ = serve_client(connection, client);
+    }
+}
+
+/// Serve a connection to a single client until it disconnects
+///
+/// # Arguments
+///
+/// `connection` - Connection to expose
+/// `client` - Socket of the client
+pub fn serve_client(connection: &mut Connection, mut client: TcpStream) -> io::Result<()> {
+    client.set_nodelay(true)?;
+    while let Some((tag, body)) = receive(&mut client)? {
+        match execute(connection, tag, &body) {
+            Ok(response) => send(&mut client, OK, &response)?,
+            Err(e) => send(&mut client, FAILED, &encode_error(&e))?,
+        }
+    }
+    Ok(())
+}
+
+// Carry out one request against the connection
+fn execute(connection: &mut Connection, tag: u8, body: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Body { data: body };
+    match tag {
+        WRITE => connection.write(body.rest()).map(|_| Vec::new()),
+        READ => {
+            let len = body.u32()? as usize;
+            check_body(len)?;
+            connection.read(len)
+        }
+        TRANSFER => connection.transfer(body.rest()),
+        TRANSACTION => {
+            let mut parts = Vec::new();
+            for _ in 0..body.u32()? {
+                let kind = body.u8()?;
+                let cs_change = body.u8()? != 0;
+                let delay_usecs = body.u16()?;
+                let speed_hz = body.u32()?;
+                let bits_per_word = body.u8()?;
+                let len = body.u32()? as usize;
+                let kind = match kind {
+                    0 => SegmentKind::Write(body.bytes(len)?),
+                    1 => {
+                        check_body(len)?;
+                        SegmentKind::Read(len)
+                    }
+                    2 => SegmentKind::Transfer(body.bytes(len)?),
+                    kind => return Err(Error::Protocol(format!("unknown segment kind {}", kind))),
+                };
+                parts.push(Segment {
+                    kind,
+                    cs_change,
+                    delay_usecs,
+                    speed_hz,
+                    bits_per_word,
+                });
+            }
+            check_body(transaction_response_len(&parts))?;
+            let rx = connection.transaction(&parts)?;
+            let mut response = (rx.len() as u32).to_be_bytes().to_vec();
+            for data in rx {
+                response.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                response.extend_from_slice(&data);
+            }
+            Ok(response)
+        }
+        SET_MODE => connection
+            .set_mode(SpiModeFlags::from_bits_truncate(body.u32()?))
+            .map(|_| Vec::new()),
+        SET_SPEED => connection.set_speed(body.u32()?).map(|_| Vec::new()),
+        SET_BITS_PER_WORD => connection.set_bits_per_word(body.u8()?).map(|_| Vec::new()),
+        CONFIG => connection.config().map(|config| encode_config(&config)),
+        tag => Err(Error::Protocol(format!("unknown request tag {:#04x}", tag))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expectation, MockStream};
+    use std::net::SocketAddr;
+    use std::thread::{self, JoinHandle};
+
+    // Server on localhost exposing a MockStream to a single client
+    fn serve_mock(expectations: Vec<Expectation>) -> (SocketAddr, MockStream, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mock = MockStream::new(expectations);
+        let served = mock.clone();
+        let server = thread::spawn(move || {
+            let (client, _) = listener.accept().unwrap();
+            serve_client(&mut Connection::new(Box::new(served)), client).unwrap();
+        });
+        (address, mock, server)
+    }
+
+    #[test]
+    fn round_trips() {
+        let (address, mock, server) = serve_mock(vec![
+            Expectation::write(&[0x06]),
+            Expectation::read(&[0x01, 0x02]),
+            Expectation::transfer(&[0x05, 0x00], &[0xff, 0x03]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x0b, 0x00]),
+                Expectation::read(&[0x11, 0x22, 0x33]),
+                Expectation::transfer(&[0x44], &[0x55]),
+            ]),
+        ]);
+        let mut stream = RemoteStream::connect(address).unwrap();
+        assert_eq!(stream.address(), address.to_string());
+        stream.write(&[0x06]).unwrap();
+        assert_eq!(stream.read(2).unwrap(), vec![0x01, 0x02]);
+        assert_eq!(stream.transfer(&[0x05, 0x00]).unwrap(), vec![0xff, 0x03]);
+        let rx = stream
+            .transaction(&[
+                Segment::write(&[0x0b, 0x00]).cs_change(true),
+                Segment::read(3).delay_usecs(10).speed_hz(1_000_000),
+                Segment::transfer(&[0x44]).bits_per_word(8),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0x11, 0x22, 0x33], vec![0x55]]);
+        drop(stream);
+        server.join().unwrap();
+        mock.done();
+    }
+
+    #[test]
+    fn config_round_trips() {
+        let (address, _mock, server) = serve_mock(vec![]);
+        let mut stream = RemoteStream::connect(address).unwrap();
+        stream.set_mode(SpiModeFlags::SPI_MODE_3).unwrap();
+        stream.set_speed(4_000_000).unwrap();
+        stream.set_bits_per_word(16).unwrap();
+        assert_eq!(
+            stream.config().unwrap(),
+            SpiConfig {
+                bits_per_word: 16,
+                max_speed_hz: 4_000_000,
+                mode: SpiModeFlags::SPI_MODE_3,
+            }
+        );
+        drop(stream);
+        server.join().unwrap();
+    }
+
+    // Server on localhost answering a single request with `tag` and `body`
+    fn serve_raw(tag: u8, body: Vec<u8>) -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            receive(&mut client).unwrap().unwrap();
+            send(&mut client, tag, &body).unwrap();
+        });
+        (address, server)
+    }
+
+    fn round_trip(error: Error) -> Error {
+        decode_error(&encode_error(&error))
+    }
+
+    #[test]
+    fn errors_round_trip() {
+        let (address, mock, server) = serve_mock(vec![Expectation::write(&[0x01])]);
+        let mut stream = RemoteStream::connect(address).unwrap();
+        match stream.write(&[0x02]) {
+            Err(Error::Protocol(msg)) => assert!(msg.contains("MockStream")),
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+        drop(stream);
+        server.join().unwrap();
+        mock.done();
+    }
+
+    #[test]
+    fn error_variants_round_trip() {
+        let os_error = round_trip(Error::Io(io::Error::from_raw_os_error(5)));
+        assert_eq!(os_error.raw_os_error(), Some(5));
+        match round_trip(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "slow"))) {
+            Error::Io(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(e.to_string(), "slow");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(Error::Transfer {
+            path: "/dev/spidev0.0".to_string(),
+            op: Operation::Transaction,
+            source: io::Error::from_raw_os_error(110),
+        }) {
+            Error::Transfer { path, op, source } => {
+                assert_eq!(path, "/dev/spidev0.0");
+                assert_eq!(op, Operation::Transaction);
+                assert_eq!(source.raw_os_error(), Some(110));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let open = round_trip(Error::Open {
+            path: "/dev/spidev1.0".to_string(),
+            source: io::Error::from_raw_os_error(2),
+        });
+        assert!(matches!(open, Error::Open { ref path, .. } if path == "/dev/spidev1.0"));
+        assert_eq!(open.raw_os_error(), Some(2));
+        let configure = round_trip(Error::Configure {
+            path: "/dev/spidev1.0".to_string(),
+            source: io::Error::from_raw_os_error(22),
+        });
+        assert!(matches!(configure, Error::Configure { .. }));
+        assert_eq!(configure.raw_os_error(), Some(22));
+        assert!(matches!(
+            round_trip(Error::LengthMismatch {
+                op: Operation::Read,
+                expected: 4,
+                actual: 2
+            }),
+            Error::LengthMismatch {
+                op: Operation::Read,
+                expected: 4,
+                actual: 2
+            }
+        ));
+        assert!(matches!(
+            round_trip(Error::Timeout {
+                op: Operation::Write
+            }),
+            Error::Timeout {
+                op: Operation::Write
+            }
+        ));
+        assert!(matches!(
+            round_trip(Error::StatusTimeout { status: vec![1, 2] }),
+            Error::StatusTimeout { status } if status == vec![1, 2]
+        ));
+        assert!(matches!(round_trip(Error::Unhealthy), Error::Unhealthy));
+        assert!(matches!(
+            round_trip(Error::Checksum {
+                expected: 1,
+                actual: 2
+            }),
+            Error::Checksum {
+                expected: 1,
+                actual: 2
+            }
+        ));
+        assert!(matches!(
+            round_trip(Error::Unsupported("cs_change".to_string())),
+            Error::Unsupported(what) if what == "cs_change"
+        ));
+        assert!(matches!(
+            round_trip(Error::Protocol("bad".to_string())),
+            Error::Protocol(msg) if msg == "bad"
+        ));
+        assert!(matches!(decode_error(&[42]), Error::Protocol(_)));
+        assert!(matches!(decode_error(&[4]), Error::Protocol(_)));
+    }
+
+    #[test]
+    fn short_transfers_are_length_mismatches() {
+        let (address, server) = serve_raw(OK, vec![0xff]);
+        let stream = RemoteStream::connect(address).unwrap();
+        assert!(matches!(
+            stream.transfer(&[0x01, 0x02]),
+            Err(Error::LengthMismatch {
+                op: Operation::Transfer,
+                expected: 2,
+                actual: 1
+            })
+        ));
+        server.join().unwrap();
+
+        let (address, server) = serve_raw(OK, vec![0xff; 3]);
+        let mut stream = RemoteStream::connect(address).unwrap();
+        assert!(matches!(
+            stream.read(2),
+            Err(Error::LengthMismatch {
+                op: Operation::Read,
+                expected: 2,
+                actual: 3
+            })
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_rejects_oversized_requests() {
+        let (address, _mock, server) = serve_mock(vec![]);
+        let mut stream = RemoteStream::connect(address).unwrap();
+        let too_long = MAX_BODY as usize + 1;
+        assert!(matches!(stream.read(too_long), Err(Error::Protocol(_))));
+        assert!(matches!(
+            stream.transfer(&vec![0; too_long]),
+            Err(Error::Protocol(_))
+        ));
+        assert!(matches!(
+            stream.transaction(&[Segment::read(too_long)]),
+            Err(Error::Protocol(_))
+        ));
+        // Nothing was sent, so the connection is still in step
+        assert_eq!(stream.config().unwrap(), SpiConfig::default());
+        drop(stream);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn server_rejects_oversized_reads() {
+        let (address, _mock, server) = serve_mock(vec![]);
+        let mut socket = TcpStream::connect(address).unwrap();
+        let too_long = MAX_BODY + 1;
+        send(&mut socket, READ, &too_long.to_be_bytes()).unwrap();
+        let (tag, body) = receive(&mut socket).unwrap().unwrap();
+        assert_eq!(tag, FAILED);
+        assert!(matches!(decode_error(&body), Error::Protocol(_)));
+
+        let mut request = 1u32.to_be_bytes().to_vec();
+        request.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        request.extend_from_slice(&too_long.to_be_bytes());
+        send(&mut socket, TRANSACTION, &request).unwrap();
+        let (tag, body) = receive(&mut socket).unwrap().unwrap();
+        assert_eq!(tag, FAILED);
+        assert!(matches!(decode_error(&body), Error::Protocol(_)));
+        drop(socket);
+        server.join().unwrap();
+    }
+}
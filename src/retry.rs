@@ -0,0 +1,435 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Stream wrapper retrying operations which fail transiently
+
+use crate::clock::Clock;
+use crate::{Error, Result, Segment, SpiConfig, Stream};
+use spidev::SpiModeFlags;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// EIO, reported by spidev when a transfer glitches
+const EIO: i32 = 5;
+
+/// Delay between attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Retry immediately
+    None,
+    /// Wait the same time before every retry
+    Fixed(Duration),
+    /// Wait `initial`, doubling before every further retry up to `max`
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl Backoff {
+    // Delay before retry number `retry`, counting from 1
+    fn delay(&self, retry: u32) -> Duration {
+        match *self {
+            Backoff::None => Duration::from_secs(0),
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(1 << (retry - 1).min(31))
+                .map_or(max, |delay| delay.min(max)),
+        }
+    }
+}
+
+/// Whether an error is worth retrying by default
+///
+/// Interrupted, would-block and timed out operations, short transfers and
+/// `EIO` from the driver are considered transient.
+pub fn is_transient(error: &Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::UnexpectedEof => true,
+        _ => error.raw_os_error() == Some(EIO),
+    }
+}
+
+/// When and how often a `RetryingStream` retries
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Attempts made in total, including the first one
+    pub max_attempts: u32,
+    /// Delay between attempts
+    pub backoff: Backoff,
+    transient: Arc<dyn Fn(&Error) -> bool + Send + Sync>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(100),
+            },
+            transient: Arc::new(is_transient),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Set the number of attempts
+    ///
+    /// # Argument
+    ///
+    /// `max_attempts` - Attempts made in total, including the first one
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the delay between attempts
+    ///
+    /// # Argument
+    ///
+    /// `backoff` - Delay between attempts
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Retry errors of the given kinds only
+    ///
+    /// # Argument
+    ///
+    /// `kinds` - Error kinds counted as transient
+    pub fn transient_kinds(self, kinds: Vec<io::ErrorKind>) -> Self {
+        self.transient(move |error| kinds.contains(&error.kind()))
+    }
+
+    /// Retry errors accepted by `f` only
+    ///
+    /// # Argument
+    ///
+    /// `f` - Decides whether an error is transient
+    pub fn transient(mut self, f: impl Fn(&Error) -> bool + Send + Sync + 'static) -> Self {
+        self.transient = Arc::new(f);
+        self
+    }
+
+    /// Whether the policy retries `error`
+    ///
+    /// # Argument
+    ///
+    /// `error` - Error an attempt failed with
+    pub fn is_transient(&self, error: &Error) -> bool {
+        (self.transient)(error)
+    }
+
+    // Run `f` until it succeeds, fails permanently or runs out of attempts,
+    // backing off on `clock`
+    fn run<R>(
+        &self,
+        stats: &RetryStats,
+        clock: &dyn Clock,
+        mut f: impl FnMut() -> Result<R>,
+    ) -> Result<R> {
+        let mut retries = 0;
+        let result = loop {
+            match f() {
+                Err(e) if retries + 1 < self.max_attempts && self.is_transient(&e) => {
+                    retries += 1;
+                    clock.sleep(self.backoff.delay(retries));
+                }
+                result => break result,
+            }
+        };
+        stats.last.store(retries, Ordering::Relaxed);
+        stats.total.fetch_add(u64::from(retries), Ordering::Relaxed);
+        result
+    }
+}
+
+/// Retry counters of a `RetryingStream`
+///
+/// Clones share the counters, so they can be kept after the stream has been
+/// moved into a `Connection`.
+#[derive(Debug, Clone, Default)]
+pub struct RetryStats {
+    last: Arc<AtomicU32>,
+    total: Arc<AtomicU64>,
+}
+
+impl RetryStats {
+    /// Retries the last operation took, whether it succeeded or not
+    pub fn last_retries(&self) -> u32 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    /// Retries taken by all operations so far
+    pub fn total_retries(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+/// Stream wrapper retrying failed operations according to a `RetryPolicy`
+///
+/// Data operations are retried, configuration changes are passed through.
+/// A stream held through `Stream::lock` keeps retrying with the same policy.
+/// How many retries each operation took is reported through `stats()`.
+pub struct RetryingStream<S> {
+    stream: S,
+    policy: RetryPolicy,
+    stats: RetryStats,
+}
+
+impl<S: Stream> RetryingStream<S> {
+    /// RetryingStream constructor
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream to wrap
+    /// `policy` - When and how often to retry
+    pub fn new(stream: S, policy: RetryPolicy) -> Self {
+        Self {
+            stream,
+            policy,
+            stats: RetryStats::default(),
+        }
+    }
+
+    /// Handle to the retry counters
+    pub fn stats(&self) -> RetryStats {
+        self.stats.clone()
+    }
+
+    /// Give back the wrapped stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream> Stream for RetryingStream<S> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let clock = self.stream.clock();
+        let stream = &mut self.stream;
+        self.policy
+            .run(&self.stats, clock.as_ref(), || stream.write(data))
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        let clock = self.stream.clock();
+        let stream = &mut self.stream;
+        self.policy
+            .run(&self.stats, clock.as_ref(), || stream.read(len))
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let clock = self.stream.clock();
+        self.policy
+            .run(&self.stats, clock.as_ref(), || self.stream.transfer(data))
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        let clock = self.stream.clock();
+        self.policy.run(&self.stats, clock.as_ref(), || {
+            self.stream.transaction(segments)
+        })
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.stream.set_mode(mode)
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.stream.set_speed(max_speed)
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.stream.set_bits_per_word(bpw)
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        self.stream.config()
+    }
+
+    fn lock(&self) -> Result<Option<Box<dyn Stream + '_>>> {
+        Ok(self.stream.lock()?.map(|stream| {
+            Box::new(RetryingStream {
+                stream,
+                policy: self.policy.clone(),
+                stats: self.stats.clone(),
+            }) as Box<dyn Stream + '_>
+        }))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.stream.clock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Expectation, MockStream, Operation, SharedBus, VirtualClock};
+    use std::cell::Cell;
+
+    // Stream timing out a number of times before passing transfers on
+    struct Flaky {
+        stream: MockStream,
+        failures: Cell<u32>,
+        clock: Arc<VirtualClock>,
+    }
+
+    impl Flaky {
+        fn new(stream: &MockStream, failures: u32) -> Self {
+            Self {
+                stream: stream.clone(),
+                failures: Cell::new(failures),
+                clock: Arc::new(VirtualClock::new()),
+            }
+        }
+    }
+
+    impl Stream for Flaky {
+        fn write(&mut self, data: &[u8]) -> Result<()> {
+            self.stream.write(data)
+        }
+
+        fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+            self.stream.read(len)
+        }
+
+        fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(Error::Timeout {
+                    op: Operation::Transfer,
+                });
+            }
+            self.stream.transfer(data)
+        }
+
+        fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+            self.stream.set_mode(mode)
+        }
+
+        fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+            self.stream.set_speed(max_speed)
+        }
+
+        fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+            self.stream.set_bits_per_word(bpw)
+        }
+
+        fn config(&self) -> Result<SpiConfig> {
+            self.stream.config()
+        }
+
+        fn clock(&self) -> Arc<dyn Clock> {
+            self.clock.clone()
+        }
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(5),
+        };
+        let delays: Vec<Duration> = (1..=4).map(|retry| backoff.delay(retry)).collect();
+        assert_eq!(delays, [1, 2, 4, 5].map(Duration::from_millis).to_vec());
+        assert_eq!(Backoff::None.delay(3), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&Error::Timeout {
+            op: Operation::Read
+        }));
+        assert!(is_transient(&Error::Io(io::Error::from_raw_os_error(EIO))));
+        assert!(!is_transient(&Error::Protocol("bad".to_string())));
+        let policy = RetryPolicy::default().transient_kinds(vec![io::ErrorKind::InvalidData]);
+        assert!(policy.is_transient(&Error::Protocol("bad".to_string())));
+        assert!(!policy.is_transient(&Error::Timeout {
+            op: Operation::Read
+        }));
+    }
+
+    #[test]
+    fn retries_with_backoff() {
+        let mock = MockStream::new(vec![Expectation::transfer(&[0x01], &[0x02])]);
+        let flaky = Flaky::new(&mock, 2);
+        let clock = flaky.clock.clone();
+        let policy = RetryPolicy::default()
+            .backoff(Backoff::Fixed(Duration::from_millis(10)))
+            .max_attempts(3);
+        let stream = RetryingStream::new(flaky, policy);
+        assert_eq!(stream.transfer(&[0x01]).unwrap(), vec![0x02]);
+        assert_eq!(stream.stats().last_retries(), 2);
+        assert_eq!(clock.elapsed(), Duration::from_millis(20));
+        mock.done();
+    }
+
+    #[test]
+    fn gives_up() {
+        let mock = MockStream::new(vec![Expectation::transfer(&[0x01], &[0x02])]);
+        let stream = RetryingStream::new(Flaky::new(&mock, 3), RetryPolicy::default());
+        assert!(matches!(
+            stream.transfer(&[0x01]),
+            Err(Error::Timeout { .. })
+        ));
+        assert_eq!(stream.stats().last_retries(), 2);
+        // Permanent errors are not retried
+        assert!(matches!(stream.transfer(&[0x03]), Err(Error::Protocol(_))));
+        assert_eq!(stream.stats().last_retries(), 0);
+        assert_eq!(stream.stats().total_retries(), 2);
+    }
+
+    #[test]
+    fn wraps_boxed_streams() {
+        let mock = MockStream::new(vec![Expectation::write(&[0x01])]);
+        let boxed: Box<dyn Stream + Send> = Box::new(mock.clone());
+        let mut connection =
+            Connection::new(Box::new(RetryingStream::new(boxed, RetryPolicy::default())));
+        connection.write(&[0x01]).unwrap();
+        mock.done();
+    }
+
+    #[test]
+    fn locked_stream_keeps_retrying() {
+        let mock = MockStream::new(vec![
+            Expectation::transfer(&[0x05], &[0x00]),
+            Expectation::transfer(&[0x05], &[0x01]),
+        ]);
+        let bus = SharedBus::new(Box::new(Flaky::new(&mock, 1)));
+        let stream = RetryingStream::new(
+            bus.device(SpiConfig::default()),
+            RetryPolicy::default().backoff(Backoff::None),
+        );
+        let stats = stream.stats();
+        let mut connection = Connection::new(Box::new(stream));
+        let status = connection
+            .exclusive(|stream| {
+                // Already holding the bus, so there is nothing left to lock
+                assert!(stream.lock()?.is_none());
+                stream.transfer(&[0x05])?;
+                stream.transfer(&[0x05])
+            })
+            .unwrap();
+        assert_eq!(status, vec![0x01]);
+        assert_eq!(stats.total_retries(), 1);
+        mock.done();
+    }
+}
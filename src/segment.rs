@@ -0,0 +1,197 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Segments making up a multi-part SPI transaction
+
+/// Direction and payload of a transaction segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind<'a> {
+    /// Clock out the given bytes, discarding what is received
+    Write(&'a [u8]),
+    /// Clock in the given number of bytes
+    Read(usize),
+    /// Clock out the given bytes and keep what is received
+    Transfer(&'a [u8]),
+}
+
+/// One part of a transaction executed with chip select held
+///
+/// Besides its payload a segment carries the per-transfer overrides of the
+/// spidev ioctl. A `speed_hz` or `bits_per_word` of 0 keeps the device setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub kind: SegmentKind<'a>,
+    /// Deselect the device after this segment, before the next one starts
+    pub cs_change: bool,
+    /// Delay after this segment, before chip select changes or the next segment starts
+    pub delay_usecs: u16,
+    /// Clock speed override in Hz
+    pub speed_hz: u32,
+    /// Bits per word override
+    pub bits_per_word: u8,
+}
+
+impl<'a> Segment<'a> {
+    fn new(kind: SegmentKind<'a>) -> Self {
+        Self {
+            kind,
+            cs_change: false,
+            delay_usecs: 0,
+            speed_hz: 0,
+            bits_per_word: 0,
+        }
+    }
+
+    /// Segment writing `data`
+    ///
+    /// # Argument
+    ///
+    /// `data` - Data to write
+    pub fn write(data: &'a [u8]) -> Self {
+        Self::new(SegmentKind::Write(data))
+    }
+
+    /// Segment reading `len` bytes
+    ///
+    /// # Argument
+    ///
+    /// `len` - Amount of Data to read
+    pub fn read(len: usize) -> Self {
+        Self::new(SegmentKind::Read(len))
+    }
+
+    /// Segment writing `data` and reading the results
+    ///
+    /// # Argument
+    ///
+    /// `data` - Data to write
+    pub fn transfer(data: &'a [u8]) -> Self {
+        Self::new(SegmentKind::Transfer(data))
+    }
+
+    /// Segment clocking no data, only waiting `usecs` microseconds
+    ///
+    /// # Argument
+    ///
+    /// `usecs` - Delay in microseconds
+    pub fn delay(usecs: u16) -> Self {
+        Self::new(SegmentKind::Write(&[])).delay_usecs(usecs)
+    }
+
+    /// Set whether to deselect the device after this segment
+    ///
+    /// # Argument
+    ///
+    /// `cs_change` - Toggle chip select after this segment
+    pub fn cs_change(mut self, cs_change: bool) -> Self {
+        self.cs_change = cs_change;
+        self
+    }
+
+    /// Set the delay after this segment
+    ///
+    /// # Argument
+    ///
+    /// `usecs` - Delay in microseconds
+    pub fn delay_usecs(mut self, usecs: u16) -> Self {
+        self.delay_usecs = usecs;
+        self
+    }
+
+    /// Set the clock speed for this segment
+    ///
+    /// # Argument
+    ///
+    /// `speed_hz` - Speed in Hz
+    pub fn speed_hz(mut self, speed_hz: u32) -> Self {
+        self.speed_hz = speed_hz;
+        self
+    }
+
+    /// Set the bits per word for this segment
+    ///
+    /// # Argument
+    ///
+    /// `bpw` - Bits per word
+    pub fn bits_per_word(mut self, bpw: u8) -> Self {
+        self.bits_per_word = bpw;
+        self
+    }
+
+    /// Whether any per-transfer override is set on this segment
+    pub fn has_overrides(&self) -> bool {
+        self.cs_change || self.delay_usecs != 0 || self.speed_hz != 0 || self.bits_per_word != 0
+    }
+
+    /// Number of bytes clocked by this segment
+    pub fn len(&self) -> usize {
+        match self.kind {
+            SegmentKind::Write(data) | SegmentKind::Transfer(data) => data.len(),
+            SegmentKind::Read(len) => len,
+        }
+    }
+
+    /// Whether this segment clocks no bytes at all
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether this segment produces a read buffer in the transaction result
+    pub fn is_read(&self) -> bool {
+        !matches!(self.kind, SegmentKind::Write(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constructors_have_no_overrides() {
+        for segment in &[
+            Segment::write(&[1, 2]),
+            Segment::read(2),
+            Segment::transfer(&[1, 2]),
+        ] {
+            assert!(!segment.has_overrides());
+            assert_eq!(segment.len(), 2);
+        }
+    }
+
+    #[test]
+    fn builders_set_overrides() {
+        let segment = Segment::read(4)
+            .cs_change(true)
+            .delay_usecs(10)
+            .speed_hz(1_000_000)
+            .bits_per_word(16);
+        assert!(segment.cs_change);
+        assert_eq!(segment.delay_usecs, 10);
+        assert_eq!(segment.speed_hz, 1_000_000);
+        assert_eq!(segment.bits_per_word, 16);
+        assert!(Segment::write(&[0]).speed_hz(1).has_overrides());
+        assert!(Segment::write(&[0]).bits_per_word(9).has_overrides());
+    }
+
+    #[test]
+    fn delay_clocks_nothing() {
+        let segment = Segment::delay(100);
+        assert!(segment.is_empty());
+        assert!(!segment.is_read());
+        assert_eq!(segment.delay_usecs, 100);
+        assert!(segment.has_overrides());
+    }
+}
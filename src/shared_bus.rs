@@ -0,0 +1,399 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// One SPI bus shared by several devices and threads
+
+use crate::clock::Clock;
+use crate::{Result, Segment, SpiConfig, Stream};
+use spidev::SpiModeFlags;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Chip select line driven in software around each transaction
+pub trait ChipSelect {
+    /// Assert the chip select line
+    fn select(&mut self) -> Result<()>;
+
+    /// Release the chip select line
+    fn deselect(&mut self) -> Result<()>;
+}
+
+struct Bus {
+    stream: Box<dyn Stream + Send>,
+    // Settings last applied to the stream, `None` if unknown
+    current: Option<SpiConfig>,
+}
+
+impl Bus {
+    fn apply(&mut self, config: &SpiConfig) -> Result<()> {
+        let current = self.current.take();
+        if current == Some(*config) {
+            self.current = current;
+            return Ok(());
+        }
+        if current.map(|c| c.mode) != Some(config.mode) {
+            self.stream.set_mode(config.mode)?;
+        }
+        if current.map(|c| c.max_speed_hz) != Some(config.max_speed_hz) {
+            self.stream.set_speed(config.max_speed_hz)?;
+        }
+        if current.map(|c| c.bits_per_word) != Some(config.bits_per_word) {
+            self.stream.set_bits_per_word(config.bits_per_word)?;
+        }
+        self.current = Some(*config);
+        Ok(())
+    }
+}
+
+/// SPI bus shared between several devices
+///
+/// The bus owns the underlying stream. Devices are accessed through
+/// `BusDevice` handles, each of which serializes whole operations under the
+/// bus lock and reconfigures the stream for its device first. When devices
+/// use software chip selects the stream should be opened with `SPI_NO_CS`.
+#[derive(Clone)]
+pub struct SharedBus {
+    bus: Arc<Mutex<Bus>>,
+}
+
+impl SharedBus {
+    /// SharedBus constructor
+    ///
+    /// # Argument
+    ///
+    /// `stream` - Stream for the physical bus, e.g. a `SpiStream`
+    pub fn new(stream: Box<dyn Stream + Send>) -> Self {
+        let current = stream.config().ok();
+        Self {
+            bus: Arc::new(Mutex::new(Bus { stream, current })),
+        }
+    }
+
+    /// Handle for a device selected by the bus' own chip select
+    ///
+    /// # Argument
+    ///
+    /// `config` - Settings the device needs
+    pub fn device(&self, config: SpiConfig) -> BusDevice {
+        BusDevice {
+            bus: self.bus.clone(),
+            config,
+            cs: None,
+        }
+    }
+
+    /// Handle for a device selected by a software chip select
+    ///
+    /// # Arguments
+    ///
+    /// `config` - Settings the device needs
+    /// `cs` - Chip select line of the device
+    pub fn device_with_cs(&self, config: SpiConfig, cs: Box<dyn ChipSelect + Send>) -> BusDevice {
+        BusDevice {
+            bus: self.bus.clone(),
+            config,
+            cs: Some(Arc::new(Mutex::new(cs))),
+        }
+    }
+}
+
+/// Handle for one device on a `SharedBus`
+///
+/// Handles can be cloned and sent to other threads. Clones share the chip
+/// select line, but configuration changes only affect the handle made on.
+/// `Stream::lock` holds the bus for several operations in a row, selecting
+/// the device around each of them.
+#[derive(Clone)]
+pub struct BusDevice {
+    bus: Arc<Mutex<Bus>>,
+    config: SpiConfig,
+    cs: Option<Arc<Mutex<Box<dyn ChipSelect + Send>>>>,
+}
+
+impl BusDevice {
+    // Lock the bus and configure it for this device
+    fn locked(&self) -> Result<LockedDevice<'_>> {
+        let mut bus = lock(&self.bus);
+        bus.apply(&self.config)?;
+        Ok(LockedDevice {
+            bus,
+            cs: self.cs.as_deref(),
+        })
+    }
+}
+
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Run `f` with `cs` selected, releasing it even if `f` fails. An error from
+// `f` takes precedence over one releasing chip select.
+pub(crate) fn selected<C: ChipSelect + ?Sized, R>(
+    cs: &mut C,
+    f: impl FnOnce() -> Result<R>,
+) -> Result<R> {
+    cs.select()?;
+    let result = f();
+    let deselected = cs.deselect();
+    let result = result?;
+    deselected?;
+    Ok(result)
+}
+
+// Run `f` with the device selected, if it has a chip select of its own
+fn device_selected<R>(
+    cs: Option<&Mutex<Box<dyn ChipSelect + Send>>>,
+    f: impl FnOnce() -> Result<R>,
+) -> Result<R> {
+    match cs {
+        Some(cs) => selected(&mut **lock(cs), f),
+        None => f(),
+    }
+}
+
+// Bus held and configured for one device, selecting it around each operation
+struct LockedDevice<'a> {
+    bus: MutexGuard<'a, Bus>,
+    cs: Option<&'a Mutex<Box<dyn ChipSelect + Send>>>,
+}
+
+impl Stream for LockedDevice<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let stream = &mut self.bus.stream;
+        device_selected(self.cs, || stream.write(data))
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        let stream = &mut self.bus.stream;
+        device_selected(self.cs, || stream.read(len))
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        device_selected(self.cs, || self.bus.stream.transfer(data))
+    }
+
+    // A software chip select is released between the groups of segments
+    // ending with `cs_change`, the bus' own chip select handles it itself
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        if self.cs.is_none() {
+            return self.bus.stream.transaction(segments);
+        }
+        let mut rx = Vec::new();
+        for group in segments.split_inclusive(|segment| segment.cs_change) {
+            let mut group = group.to_vec();
+            if let Some(last) = group.last_mut() {
+                last.cs_change = false;
+            }
+            rx.extend(device_selected(self.cs, || {
+                self.bus.stream.transaction(&group)
+            })?);
+        }
+        Ok(rx)
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.bus.stream.clock()
+    }
+}
+
+impl Stream for BusDevice {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.locked()?.write(data)
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.locked()?.read(len)
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.locked()?.transfer(data)
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        self.locked()?.transaction(segments)
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.config.mode = mode;
+        Ok(())
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.config.max_speed_hz = max_speed;
+        Ok(())
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.config.bits_per_word = bpw;
+        Ok(())
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        Ok(self.config)
+    }
+
+    fn lock(&self) -> Result<Option<Box<dyn Stream + '_>>> {
+        Ok(Some(Box::new(self.locked()?)))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        lock(&self.bus).stream.clock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Error, Expectation, MockStream};
+
+    // Chip select logging its changes
+    struct Recorder {
+        name: char,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ChipSelect for Recorder {
+        fn select(&mut self) -> Result<()> {
+            lock(&self.log).push(format!("{}+", self.name));
+            Ok(())
+        }
+
+        fn deselect(&mut self) -> Result<()> {
+            lock(&self.log).push(format!("{}-", self.name));
+            Ok(())
+        }
+    }
+
+    fn config(max_speed_hz: u32, mode: SpiModeFlags) -> SpiConfig {
+        SpiConfig {
+            bits_per_word: 8,
+            max_speed_hz,
+            mode,
+        }
+    }
+
+    #[test]
+    fn devices_apply_their_settings() {
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x01]),
+            Expectation::write(&[0x02]),
+        ]);
+        let bus = SharedBus::new(Box::new(mock.clone()));
+        let mut a = bus.device(config(1_000_000, SpiModeFlags::SPI_MODE_0));
+        let mut b = bus.device(config(500_000, SpiModeFlags::SPI_MODE_3));
+        a.write(&[0x01]).unwrap();
+        assert_eq!(
+            mock.config().unwrap(),
+            config(1_000_000, SpiModeFlags::SPI_MODE_0)
+        );
+        b.write(&[0x02]).unwrap();
+        assert_eq!(
+            mock.config().unwrap(),
+            config(500_000, SpiModeFlags::SPI_MODE_3)
+        );
+        mock.done();
+    }
+
+    #[test]
+    fn settings_are_per_handle() {
+        let bus = SharedBus::new(Box::new(MockStream::new(vec![])));
+        let mut a = bus.device(config(1_000_000, SpiModeFlags::SPI_MODE_0));
+        let b = a.clone();
+        a.set_speed(2_000_000).unwrap();
+        assert_eq!(a.config().unwrap().max_speed_hz, 2_000_000);
+        assert_eq!(b.config().unwrap().max_speed_hz, 1_000_000);
+    }
+
+    #[test]
+    fn chip_select_wraps_each_operation() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x01]),
+            Expectation::transfer(&[0x02], &[0x03]),
+        ]);
+        let bus = SharedBus::new(Box::new(mock.clone()));
+        let cs = |name| {
+            Box::new(Recorder {
+                name,
+                log: log.clone(),
+            })
+        };
+        let mut a = bus.device_with_cs(SpiConfig::default(), cs('a'));
+        let b = bus.device_with_cs(SpiConfig::default(), cs('b'));
+        a.write(&[0x01]).unwrap();
+        assert_eq!(b.transfer(&[0x02]).unwrap(), vec![0x03]);
+        // A failed operation still releases chip select
+        assert!(matches!(a.write(&[0x04]), Err(Error::Protocol(_))));
+        assert_eq!(*lock(&log), ["a+", "a-", "b+", "b-", "a+", "a-"]);
+        mock.done();
+    }
+
+    #[test]
+    fn transactions_split_at_cs_change() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mock = MockStream::new(vec![
+            Expectation::transaction(vec![Expectation::write(&[0x06])]),
+            Expectation::transaction(vec![
+                Expectation::write(&[0x02, 0x00]),
+                Expectation::read(&[0xaa]),
+            ]),
+        ]);
+        let bus = SharedBus::new(Box::new(mock.clone()));
+        let cs = Box::new(Recorder {
+            name: 'a',
+            log: log.clone(),
+        });
+        let device = bus.device_with_cs(SpiConfig::default(), cs);
+        let rx = device
+            .transaction(&[
+                Segment::write(&[0x06]).cs_change(true),
+                Segment::write(&[0x02, 0x00]),
+                Segment::read(1),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0xaa]]);
+        assert_eq!(*lock(&log), ["a+", "a-", "a+", "a-"]);
+        mock.done();
+    }
+
+    #[test]
+    fn exclusive_holds_the_bus() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x06]),
+            Expectation::read(&[0x02]),
+        ]);
+        let bus = SharedBus::new(Box::new(mock.clone()));
+        let cs = Box::new(Recorder {
+            name: 'a',
+            log: log.clone(),
+        });
+        let mut connection =
+            Connection::new(Box::new(bus.device_with_cs(SpiConfig::default(), cs)));
+        let other = bus.device(SpiConfig::default());
+        let status = connection
+            .exclusive(|stream| {
+                // The bus is held, so other handles cannot get in
+                assert!(other.bus.try_lock().is_err());
+                stream.write(&[0x06])?;
+                stream.read(1)
+            })
+            .unwrap();
+        assert_eq!(status, vec![0x02]);
+        assert!(other.bus.try_lock().is_ok());
+        assert_eq!(*lock(&log), ["a+", "a-", "a+", "a-"]);
+        mock.done();
+    }
+}
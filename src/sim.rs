@@ -0,0 +1,520 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Simulated SPI bus hosting modelled devices
+//
+// Unlike `MockStream`, which checks a script of expected bytes, the simulated
+// bus forwards the bytes of every operation to device models which keep
+// their own state:
+//
+//     let bus = SimulatedBus::new();
+//     let sensor = bus.attach(0, MySensor::default());
+//     let mut connection = Connection::new(Box::new(bus.with_slot(0)));
+//
+// `sensor` stays available to the test for inspecting or changing the model.
+// Models of common parts live in the submodules.
+//
+// The bus runs on a `VirtualClock`, which advances by the time each byte
+// takes at the configured clock speed and by segment delays. Models get the
+// clock when attached and streams report it, so deadlines of polling helpers
+// and device latencies play out in virtual time.
+
+pub mod eeprom;
+mod memory;
+pub mod nor_flash;
+
+use crate::clock::{Clock, VirtualClock};
+use crate::{unsupported, Result, Segment, SegmentKind, SpiConfig, Stream};
+use spidev::SpiModeFlags;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Model of a device on a `SimulatedBus`
+///
+/// The bus asserts chip select, exchanges bytes one at a time and releases
+/// chip select again, calling the matching methods in that order.
+pub trait SimDevice: Send {
+    /// The device was attached to a bus
+    ///
+    /// Models with timing behaviour keep `clock` and read the time from it.
+    ///
+    /// # Argument
+    ///
+    /// `clock` - Virtual clock of the bus
+    fn attach(&mut self, _clock: Arc<dyn Clock>) {}
+
+    /// Chip select was asserted
+    fn select(&mut self) {}
+
+    /// Chip select was released
+    fn deselect(&mut self) {}
+
+    /// Exchange one byte, returning the byte driven on MISO
+    ///
+    /// # Argument
+    ///
+    /// `mosi` - Byte received from the master
+    fn exchange(&mut self, mosi: u8) -> u8;
+}
+
+// Empty slot, leaving MISO pulled high
+struct Floating;
+
+impl SimDevice for Floating {
+    fn exchange(&mut self, _mosi: u8) -> u8 {
+        0xff
+    }
+}
+
+type Slot = Option<Arc<Mutex<dyn SimDevice>>>;
+
+struct Bus {
+    slots: Vec<Slot>,
+    clock: Arc<VirtualClock>,
+}
+
+impl Bus {
+    // Perform `segments` against the device in `slot`, clocked at `speed` Hz
+    fn run(&self, slot: usize, speed: u32, segments: &[Segment]) -> Vec<Vec<u8>> {
+        let slot = self.slots.get(slot).cloned().flatten();
+        let mut guard;
+        let device: &mut dyn SimDevice = match &slot {
+            Some(device) => {
+                guard = lock(device);
+                &mut *guard
+            }
+            None => &mut Floating,
+        };
+        device.select();
+        let mut rx = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            let byte_time = byte_time(match segment.speed_hz {
+                0 => speed,
+                segment_speed => segment_speed,
+            });
+            let mut exchange = |byte| {
+                self.clock.advance(byte_time);
+                device.exchange(byte)
+            };
+            let data: Vec<u8> = match segment.kind {
+                SegmentKind::Write(data) | SegmentKind::Transfer(data) => {
+                    data.iter().map(|&byte| exchange(byte)).collect()
+                }
+                SegmentKind::Read(len) => (0..len).map(|_| exchange(0)).collect(),
+            };
+            if segment.is_read() {
+                rx.push(data);
+            }
+            self.clock
+                .advance(Duration::from_micros(segment.delay_usecs.into()));
+            if segment.cs_change && i + 1 < segments.len() {
+                device.deselect();
+                device.select();
+            }
+        }
+        device.deselect();
+        rx
+    }
+}
+
+// Time taken by 8 bits at `speed` Hz, none if the speed is unknown
+fn byte_time(speed: u32) -> Duration {
+    match speed {
+        0 => Duration::from_secs(0),
+        speed => Duration::from_nanos(8_000_000_000 / u64::from(speed)),
+    }
+}
+
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Simulated SPI bus with one device model per chip select slot
+///
+/// A handle performs operations on one slot, slot 0 unless created with
+/// `with_slot`. Handles share the bus and its clock, and empty slots read as
+/// `0xff`. Time only passes while the clock speed is set. Words are always 8
+/// bits, other word sizes are rejected as unsupported.
+#[derive(Clone)]
+pub struct SimulatedBus {
+    bus: Arc<Mutex<Bus>>,
+    slot: usize,
+    config: SpiConfig,
+}
+
+impl Default for SimulatedBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedBus {
+    /// SimulatedBus constructor, with all slots empty
+    pub fn new() -> Self {
+        Self {
+            bus: Arc::new(Mutex::new(Bus {
+                slots: Vec::new(),
+                clock: Arc::new(VirtualClock::new()),
+            })),
+            slot: 0,
+            config: SpiConfig::default(),
+        }
+    }
+
+    /// Put a device model in a slot, replacing any previous one
+    ///
+    /// Returns a handle to the model for inspecting its state.
+    ///
+    /// # Arguments
+    ///
+    /// `slot` - Chip select slot
+    /// `device` - Device model
+    pub fn attach<D: SimDevice + 'static>(&self, slot: usize, mut device: D) -> Arc<Mutex<D>> {
+        let mut bus = lock(&self.bus);
+        device.attach(bus.clock.clone());
+        let device = Arc::new(Mutex::new(device));
+        if bus.slots.len() <= slot {
+            bus.slots.resize_with(slot + 1, || None);
+        }
+        bus.slots[slot] = Some(device.clone());
+        device
+    }
+
+    /// Remove the device model from a slot
+    ///
+    /// # Argument
+    ///
+    /// `slot` - Chip select slot
+    pub fn detach(&self, slot: usize) {
+        if let Some(entry) = lock(&self.bus).slots.get_mut(slot) {
+            *entry = None;
+        }
+    }
+
+    /// Handle performing operations on another slot
+    ///
+    /// # Argument
+    ///
+    /// `slot` - Chip select slot
+    pub fn with_slot(&self, slot: usize) -> Self {
+        Self {
+            bus: self.bus.clone(),
+            slot,
+            config: self.config,
+        }
+    }
+
+    /// Chip select slot of this handle
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Clock of the bus, for letting time pass between operations
+    pub fn virtual_clock(&self) -> Arc<VirtualClock> {
+        lock(&self.bus).clock.clone()
+    }
+}
+
+// Bus held for one slot
+struct LockedSlot<'a> {
+    bus: MutexGuard<'a, Bus>,
+    slot: usize,
+    speed: u32,
+}
+
+impl Stream for LockedSlot<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.transfer(data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.transfer(&vec![0; len])
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut rx = self.transaction(&[Segment::transfer(data)])?;
+        Ok(rx.pop().unwrap_or_default())
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        if segments
+            .iter()
+            .any(|s| s.bits_per_word != 0 && s.bits_per_word != 8)
+        {
+            return Err(unsupported("bits per word other than 8"));
+        }
+        Ok(self.bus.run(self.slot, self.speed, segments))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.bus.clock.clone()
+    }
+}
+
+impl SimulatedBus {
+    fn locked(&self) -> LockedSlot<'_> {
+        LockedSlot {
+            bus: lock(&self.bus),
+            slot: self.slot,
+            speed: self.config.max_speed_hz,
+        }
+    }
+}
+
+impl Stream for SimulatedBus {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.locked().write(data)
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.locked().read(len)
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.locked().transfer(data)
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        self.locked().transaction(segments)
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.config.mode = mode;
+        Ok(())
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.config.max_speed_hz = max_speed;
+        Ok(())
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        if bpw != 8 {
+            return Err(unsupported("bits per word other than 8"));
+        }
+        Ok(())
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        Ok(self.config)
+    }
+
+    fn lock(&self) -> Result<Option<Box<dyn Stream + '_>>> {
+        Ok(Some(Box::new(self.locked())))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.virtual_clock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nor_flash::{NorFlash, NorFlashConfig, STATUS_WIP};
+    use super::*;
+    use crate::{Connection, Error};
+    use std::time::Instant;
+
+    struct Echo;
+
+    impl SimDevice for Echo {
+        fn exchange(&mut self, mosi: u8) -> u8 {
+            mosi
+        }
+    }
+
+    // Device logging the calls it gets, answering with its name
+    struct Logger {
+        name: u8,
+        log: Vec<String>,
+    }
+
+    impl Logger {
+        fn new(name: u8) -> Self {
+            Self {
+                name,
+                log: Vec::new(),
+            }
+        }
+    }
+
+    impl SimDevice for Logger {
+        fn select(&mut self) {
+            self.log.push("select".to_string());
+        }
+
+        fn deselect(&mut self) {
+            self.log.push("deselect".to_string());
+        }
+
+        fn exchange(&mut self, mosi: u8) -> u8 {
+            self.log.push(format!("{:02x}", mosi));
+            self.name
+        }
+    }
+
+    fn slow_erase() -> NorFlashConfig {
+        let secs = Duration::from_secs;
+        NorFlashConfig::default().erase_times(secs(0), secs(0), secs(20))
+    }
+
+    #[test]
+    fn operations_reach_the_device_in_the_slot() {
+        let bus = SimulatedBus::new();
+        let a = bus.attach(0, Logger::new(0xaa));
+        let b = bus.attach(2, Logger::new(0xbb));
+        let mut second = bus.with_slot(2);
+        assert_eq!(bus.slot(), 0);
+        assert_eq!(second.slot(), 2);
+        assert_eq!(bus.transfer(&[0x01]).unwrap(), vec![0xaa]);
+        assert_eq!(second.read(2).unwrap(), vec![0xbb, 0xbb]);
+        second.write(&[0x02]).unwrap();
+        assert_eq!(a.lock().unwrap().log, ["select", "01", "deselect"]);
+        assert_eq!(
+            b.lock().unwrap().log,
+            ["select", "00", "00", "deselect", "select", "02", "deselect"]
+        );
+    }
+
+    #[test]
+    fn empty_slots_float_high() {
+        let bus = SimulatedBus::new();
+        bus.attach(0, Echo);
+        assert_eq!(bus.with_slot(1).transfer(&[0x00]).unwrap(), vec![0xff]);
+        assert_eq!(bus.with_slot(7).transfer(&[0x12]).unwrap(), vec![0xff]);
+    }
+
+    #[test]
+    fn cs_change_toggles_chip_select() {
+        let bus = SimulatedBus::new();
+        let device = bus.attach(0, Logger::new(0x55));
+        let rx = bus
+            .transaction(&[
+                Segment::write(&[0x06]).cs_change(true),
+                Segment::write(&[0x02]),
+                Segment::read(1).cs_change(true),
+            ])
+            .unwrap();
+        assert_eq!(rx, vec![vec![0x55]]);
+        // A trailing cs_change does not leave the device selected
+        assert_eq!(
+            device.lock().unwrap().log,
+            ["select", "06", "deselect", "select", "02", "00", "deselect"]
+        );
+    }
+
+    #[test]
+    fn detached_devices_no_longer_answer() {
+        let bus = SimulatedBus::new();
+        let device = bus.attach(0, Logger::new(0x55));
+        assert_eq!(bus.transfer(&[0x01]).unwrap(), vec![0x55]);
+        bus.detach(0);
+        bus.detach(3);
+        assert_eq!(bus.transfer(&[0x02]).unwrap(), vec![0xff]);
+        assert_eq!(device.lock().unwrap().log, ["select", "01", "deselect"]);
+    }
+
+    #[test]
+    fn only_8_bits_per_word() {
+        let mut bus = SimulatedBus::new();
+        bus.set_bits_per_word(8).unwrap();
+        assert!(matches!(
+            bus.set_bits_per_word(16),
+            Err(Error::Unsupported(_))
+        ));
+        assert_eq!(bus.config().unwrap().bits_per_word, 8);
+        assert!(matches!(
+            bus.transaction(&[Segment::write(&[0]).bits_per_word(9)]),
+            Err(Error::Unsupported(_))
+        ));
+        bus.transaction(&[Segment::write(&[0]).bits_per_word(8)])
+            .unwrap();
+    }
+
+    #[test]
+    fn byte_time_follows_the_speed() {
+        assert_eq!(byte_time(1_000_000), Duration::from_micros(8));
+        assert_eq!(byte_time(8_000_000), Duration::from_micros(1));
+        assert_eq!(byte_time(0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn clock_advances_at_the_configured_speed() {
+        let mut bus = SimulatedBus::new();
+        bus.attach(0, Echo);
+        let clock = bus.virtual_clock();
+        bus.write(&[0x42]).unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(0));
+        bus.set_speed(1_000_000).unwrap();
+        bus.write(&[0x42]).unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_micros(8));
+        bus.transaction(&[
+            Segment::write(&[0x42, 0x43]).speed_hz(500_000),
+            Segment::delay(10),
+        ])
+        .unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_micros(8 + 32 + 10));
+    }
+
+    #[test]
+    fn chip_erase_completes_in_virtual_time() {
+        let bus = SimulatedBus::new();
+        let flash = bus.attach(0, NorFlash::new(slow_erase()).unwrap());
+        flash.lock().unwrap().memory_mut().fill(0);
+        let clock = bus.virtual_clock();
+        let mut connection = Connection::new(Box::new(bus));
+        connection.write(&[0x06]).unwrap();
+        connection.write(&[0xc7]).unwrap();
+        let started = clock.elapsed();
+        let wall = Instant::now();
+        let status = connection
+            .poll_until_masked(
+                &[0x05],
+                STATUS_WIP,
+                0,
+                Duration::from_millis(10),
+                Duration::from_secs(30),
+            )
+            .unwrap();
+        assert_eq!(status, 0);
+        assert!(wall.elapsed() < Duration::from_secs(1));
+        let waited = clock.elapsed() - started;
+        assert!(waited >= Duration::from_secs(20));
+        assert!(waited < Duration::from_secs(21));
+        assert!(flash.lock().unwrap().memory().iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn polling_times_out_in_virtual_time() {
+        let bus = SimulatedBus::new();
+        bus.attach(0, NorFlash::new(slow_erase()).unwrap());
+        let clock = bus.virtual_clock();
+        let mut connection = Connection::new(Box::new(bus));
+        connection.write(&[0x06]).unwrap();
+        connection.write(&[0xc7]).unwrap();
+        let started = clock.elapsed();
+        let result = connection.poll_until_masked(
+            &[0x05],
+            STATUS_WIP,
+            0,
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+        );
+        assert!(matches!(result, Err(Error::StatusTimeout { .. })));
+        assert_eq!(clock.elapsed() - started, Duration::from_secs(1));
+    }
+}
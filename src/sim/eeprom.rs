@@ -0,0 +1,536 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Models of 25xx series SPI EEPROMs and MB85RS series FRAMs
+//
+// Both share the 25xx command set:
+//
+//     0x06 write enable       0x04 write disable
+//     0x05 read status        0x01 write status
+//     0x03 read               0x02 write
+//
+// and FRAMs additionally answer 0x9f with their device ID. Writes need the
+// write enable latch, which is cleared again after every write. The status
+// register holds WIP (bit 0), WEL (bit 1), the block protect bits BP0 and BP1
+// (bits 2 and 3) and WPEN (bit 7). Block protection covers the upper quarter,
+// upper half or all of the array; writes to protected addresses are ignored.
+//
+// An EEPROM latches written bytes into a page buffer, wrapping around within
+// the page, and programs them once chip select is released. It then stays
+// busy for the write time, ignoring everything but status reads. A FRAM
+// writes every byte as it arrives, continuing across pages, and is never busy.
+
+use crate::clock::Clock;
+use crate::sim::memory::{check_power_of_two, Frame, WriteCycle};
+use crate::sim::SimDevice;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+const WRITE_ENABLE: u8 = 0x06;
+const WRITE_DISABLE: u8 = 0x04;
+const READ_STATUS: u8 = 0x05;
+const WRITE_STATUS: u8 = 0x01;
+const READ: u8 = 0x03;
+const WRITE: u8 = 0x02;
+const READ_ID: u8 = 0x9f;
+
+pub use crate::sim::memory::{STATUS_WEL, STATUS_WIP};
+
+/// Status register block protect bits
+pub const STATUS_BP: u8 = 0x0c;
+/// Status register write protect enable bit
+pub const STATUS_WPEN: u8 = 0x80;
+
+/// Geometry and timing of a simulated EEPROM
+///
+/// The default resembles a 25LC256 whose writes complete instantly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EepromConfig {
+    /// Capacity in bytes, a power of two
+    pub size: usize,
+    /// Page size in bytes, a power of two
+    pub page_size: usize,
+    /// Number of address bytes, 1 to 3
+    pub address_bytes: usize,
+    /// Duration of a write cycle
+    pub write_time: Duration,
+}
+
+impl Default for EepromConfig {
+    fn default() -> Self {
+        Self {
+            size: 32 << 10,
+            page_size: 64,
+            address_bytes: 2,
+            write_time: Duration::from_secs(0),
+        }
+    }
+}
+
+impl EepromConfig {
+    /// Set the geometry
+    ///
+    /// # Arguments
+    ///
+    /// `size` - Capacity in bytes
+    /// `page_size` - Page size in bytes
+    /// `address_bytes` - Number of address bytes
+    pub fn geometry(mut self, size: usize, page_size: usize, address_bytes: usize) -> Self {
+        self.size = size;
+        self.page_size = page_size;
+        self.address_bytes = address_bytes;
+        self
+    }
+
+    /// Set the duration of a write cycle
+    ///
+    /// # Argument
+    ///
+    /// `write_time` - Duration of a write cycle
+    pub fn write_time(mut self, write_time: Duration) -> Self {
+        self.write_time = write_time;
+        self
+    }
+}
+
+/// Geometry of a simulated FRAM
+///
+/// The default resembles an MB85RS256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramConfig {
+    /// Capacity in bytes, a power of two
+    pub size: usize,
+    /// Number of address bytes, 1 to 3
+    pub address_bytes: usize,
+    /// Manufacturer ID, continuation code and product ID bytes
+    pub device_id: [u8; 4],
+}
+
+impl Default for FramConfig {
+    fn default() -> Self {
+        Self {
+            size: 32 << 10,
+            address_bytes: 2,
+            device_id: [0x04, 0x7f, 0x05, 0x09],
+        }
+    }
+}
+
+impl FramConfig {
+    /// Set the geometry
+    ///
+    /// # Arguments
+    ///
+    /// `size` - Capacity in bytes
+    /// `address_bytes` - Number of address bytes
+    pub fn geometry(mut self, size: usize, address_bytes: usize) -> Self {
+        self.size = size;
+        self.address_bytes = address_bytes;
+        self
+    }
+
+    /// Set the device ID
+    ///
+    /// # Argument
+    ///
+    /// `device_id` - Bytes answered to the read ID command
+    pub fn device_id(mut self, device_id: [u8; 4]) -> Self {
+        self.device_id = device_id;
+        self
+    }
+}
+
+// State shared by both kinds of memory
+struct Memory25 {
+    memory: Vec<u8>,
+    address_bytes: usize,
+    // Page size of an EEPROM, `None` for a FRAM
+    page_size: Option<usize>,
+    write_time: Duration,
+    device_id: Option<[u8; 4]>,
+    // Block protect and WPEN bits of the status register
+    protection: u8,
+    write: WriteCycle,
+    frame: Frame,
+    // Status byte latched by a status write
+    new_status: Option<u8>,
+}
+
+impl Memory25 {
+    fn new(
+        size: usize,
+        address_bytes: usize,
+        page_size: Option<usize>,
+        write_time: Duration,
+        device_id: Option<[u8; 4]>,
+    ) -> Self {
+        Self {
+            memory: vec![0xff; size],
+            address_bytes: address_bytes.clamp(1, 3),
+            page_size,
+            write_time,
+            device_id,
+            protection: 0,
+            write: WriteCycle::new(),
+            frame: Frame::new(),
+            new_status: None,
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        self.protection | self.write.status()
+    }
+
+    fn is_protected(&self, address: usize) -> bool {
+        let size = self.memory.len();
+        match (self.protection & STATUS_BP) >> 2 {
+            0 => false,
+            1 => address >= size - size / 4,
+            2 => address >= size / 2,
+            _ => true,
+        }
+    }
+
+    fn select(&mut self) {
+        self.frame.reset();
+        self.new_status = None;
+    }
+
+    fn deselect(&mut self) {
+        let command = match self.frame.finish() {
+            Some(command) => command,
+            None => return,
+        };
+        if self.write.is_busy() {
+            return;
+        }
+        match command {
+            WRITE_ENABLE => self.write.enabled = true,
+            WRITE_DISABLE => self.write.enabled = false,
+            _ if !self.write.enabled => {}
+            WRITE_STATUS => {
+                if let Some(status) = self.new_status {
+                    self.protection = status & (STATUS_BP | STATUS_WPEN);
+                    self.write.start(self.write_time);
+                }
+            }
+            WRITE if self.frame.received() > self.address_bytes => {
+                if let Some(page_size) = self.page_size {
+                    let latched: Vec<(usize, u8)> = self.frame.latched(page_size).collect();
+                    for (address, byte) in latched {
+                        if !self.is_protected(address) {
+                            self.memory[address] = byte;
+                        }
+                    }
+                }
+                self.write.start(self.write_time);
+            }
+            _ => {}
+        }
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        let (command, received) = match self.frame.receive(mosi) {
+            Some(frame) => frame,
+            None => return 0xff,
+        };
+        if command == READ_STATUS {
+            return self.status();
+        }
+        if self.write.is_busy() {
+            return 0xff;
+        }
+        match command {
+            READ_ID => self
+                .device_id
+                .and_then(|id| id.get(received).copied())
+                .unwrap_or(0xff),
+            WRITE_STATUS => {
+                if received == 0 {
+                    self.new_status = Some(mosi);
+                }
+                0xff
+            }
+            READ | WRITE if received < self.address_bytes => {
+                self.frame.shift_address(mosi, self.memory.len());
+                0xff
+            }
+            READ => {
+                let byte = self.memory[self.frame.address];
+                self.frame.address = (self.frame.address + 1) & (self.memory.len() - 1);
+                byte
+            }
+            WRITE => {
+                match self.page_size {
+                    Some(page_size) => {
+                        self.frame
+                            .latch(received - self.address_bytes, mosi, page_size)
+                    }
+                    None => {
+                        let address = self.frame.address;
+                        if self.write.enabled && !self.is_protected(address) {
+                            self.memory[address] = mosi;
+                        }
+                        self.frame.address = (address + 1) & (self.memory.len() - 1);
+                    }
+                }
+                0xff
+            }
+            _ => 0xff,
+        }
+    }
+}
+
+/// Simulated 25xx series SPI EEPROM, e.g. 25LC256 or AT25256
+pub struct Eeprom {
+    config: EepromConfig,
+    state: Memory25,
+}
+
+impl Eeprom {
+    /// Eeprom constructor, with every byte reading 0xff
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` unless the size and page size
+    /// are non-zero powers of two.
+    ///
+    /// # Argument
+    ///
+    /// `config` - Geometry and timing
+    pub fn new(config: EepromConfig) -> io::Result<Self> {
+        check_power_of_two("size", config.size)?;
+        check_power_of_two("page size", config.page_size)?;
+        Ok(Self {
+            config,
+            state: Memory25::new(
+                config.size,
+                config.address_bytes,
+                Some(config.page_size),
+                config.write_time,
+                None,
+            ),
+        })
+    }
+
+    /// Geometry and timing
+    pub fn config(&self) -> &EepromConfig {
+        &self.config
+    }
+
+    /// Contents of the memory
+    pub fn memory(&self) -> &[u8] {
+        &self.state.memory
+    }
+
+    /// Contents of the memory, for preloading it
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.state.memory
+    }
+
+    /// Whether a write cycle is in progress
+    pub fn is_busy(&mut self) -> bool {
+        self.state.write.is_busy()
+    }
+}
+
+impl SimDevice for Eeprom {
+    fn attach(&mut self, clock: Arc<dyn Clock>) {
+        self.state.write.attach(clock);
+    }
+
+    fn select(&mut self) {
+        self.state.select()
+    }
+
+    fn deselect(&mut self) {
+        self.state.deselect()
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        self.state.exchange(mosi)
+    }
+}
+
+/// Simulated MB85RS series SPI FRAM
+pub struct Fram {
+    config: FramConfig,
+    state: Memory25,
+}
+
+impl Fram {
+    /// Fram constructor, with every byte reading 0xff
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` unless the size is a non-zero
+    /// power of two.
+    ///
+    /// # Argument
+    ///
+    /// `config` - Geometry
+    pub fn new(config: FramConfig) -> io::Result<Self> {
+        check_power_of_two("size", config.size)?;
+        Ok(Self {
+            config,
+            state: Memory25::new(
+                config.size,
+                config.address_bytes,
+                None,
+                Duration::from_secs(0),
+                Some(config.device_id),
+            ),
+        })
+    }
+
+    /// Geometry
+    pub fn config(&self) -> &FramConfig {
+        &self.config
+    }
+
+    /// Contents of the memory
+    pub fn memory(&self) -> &[u8] {
+        &self.state.memory
+    }
+
+    /// Contents of the memory, for preloading it
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.state.memory
+    }
+}
+
+impl SimDevice for Fram {
+    fn attach(&mut self, clock: Arc<dyn Clock>) {
+        self.state.write.attach(clock);
+    }
+
+    fn select(&mut self) {
+        self.state.select()
+    }
+
+    fn deselect(&mut self) {
+        self.state.deselect()
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        self.state.exchange(mosi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+
+    // Run one chip select frame, returning the bytes answered
+    fn frame(device: &mut impl SimDevice, mosi: &[u8]) -> Vec<u8> {
+        device.select();
+        let miso = mosi.iter().map(|&byte| device.exchange(byte)).collect();
+        device.deselect();
+        miso
+    }
+
+    fn read(device: &mut impl SimDevice, address: u16, len: usize) -> Vec<u8> {
+        let mut mosi = vec![READ, (address >> 8) as u8, address as u8];
+        mosi.resize(3 + len, 0);
+        frame(device, &mosi).split_off(3)
+    }
+
+    #[test]
+    fn sizes_must_be_powers_of_two() {
+        let eeprom = |size, page_size| {
+            let config = EepromConfig::default().geometry(size, page_size, 2);
+            Eeprom::new(config).err().unwrap().kind()
+        };
+        assert_eq!(eeprom(0, 64), io::ErrorKind::InvalidInput);
+        assert_eq!(eeprom(24 << 10, 64), io::ErrorKind::InvalidInput);
+        assert_eq!(eeprom(32 << 10, 0), io::ErrorKind::InvalidInput);
+        assert_eq!(eeprom(32 << 10, 48), io::ErrorKind::InvalidInput);
+        let fram = |size| {
+            Fram::new(FramConfig::default().geometry(size, 2))
+                .err()
+                .unwrap()
+                .kind()
+        };
+        assert_eq!(fram(0), io::ErrorKind::InvalidInput);
+        assert_eq!(fram(100), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn eeprom_write_wraps_within_the_page() {
+        let mut eeprom = Eeprom::new(EepromConfig::default()).unwrap();
+        frame(&mut eeprom, &[WRITE_ENABLE]);
+        assert_eq!(
+            frame(&mut eeprom, &[READ_STATUS, 0]),
+            vec![0xff, STATUS_WEL]
+        );
+        frame(&mut eeprom, &[WRITE, 0x00, 0x3f, 1, 2]);
+        assert_eq!(read(&mut eeprom, 0x003f, 1), vec![1]);
+        assert_eq!(read(&mut eeprom, 0x0000, 1), vec![2]);
+        assert_eq!(read(&mut eeprom, 0x0040, 1), vec![0xff]);
+        assert_eq!(frame(&mut eeprom, &[READ_STATUS, 0]), vec![0xff, 0]);
+    }
+
+    #[test]
+    fn eeprom_write_needs_the_latch() {
+        let mut eeprom = Eeprom::new(EepromConfig::default()).unwrap();
+        frame(&mut eeprom, &[WRITE, 0x00, 0x10, 0x42]);
+        assert_eq!(read(&mut eeprom, 0x0010, 1), vec![0xff]);
+    }
+
+    #[test]
+    fn eeprom_is_busy_for_the_write_time() {
+        let config = EepromConfig::default().write_time(Duration::from_millis(5));
+        let mut eeprom = Eeprom::new(config).unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        eeprom.attach(clock.clone());
+        frame(&mut eeprom, &[WRITE_ENABLE]);
+        frame(&mut eeprom, &[WRITE, 0x00, 0x00, 0x42]);
+        assert!(eeprom.is_busy());
+        assert_eq!(
+            frame(&mut eeprom, &[READ_STATUS, 0]),
+            vec![0xff, STATUS_WIP | STATUS_WEL]
+        );
+        assert_eq!(read(&mut eeprom, 0x0000, 1), vec![0xff]);
+        clock.advance(Duration::from_millis(5));
+        assert!(!eeprom.is_busy());
+        assert_eq!(read(&mut eeprom, 0x0000, 1), vec![0x42]);
+    }
+
+    #[test]
+    fn block_protection_ignores_writes() {
+        let mut eeprom = Eeprom::new(EepromConfig::default()).unwrap();
+        frame(&mut eeprom, &[WRITE_ENABLE]);
+        frame(&mut eeprom, &[WRITE_STATUS, 0x04]);
+        assert_eq!(frame(&mut eeprom, &[READ_STATUS, 0]), vec![0xff, 0x04]);
+        frame(&mut eeprom, &[WRITE_ENABLE]);
+        frame(&mut eeprom, &[WRITE, 0x60, 0x00, 0x11]);
+        frame(&mut eeprom, &[WRITE_ENABLE]);
+        frame(&mut eeprom, &[WRITE, 0x5f, 0xff, 0x22]);
+        assert_eq!(read(&mut eeprom, 0x6000, 1), vec![0xff]);
+        assert_eq!(read(&mut eeprom, 0x5fff, 1), vec![0x22]);
+    }
+
+    #[test]
+    fn fram_writes_across_pages_and_reports_its_id() {
+        let mut fram = Fram::new(FramConfig::default()).unwrap();
+        assert_eq!(
+            frame(&mut fram, &[READ_ID, 0, 0, 0, 0]),
+            vec![0xff, 0x04, 0x7f, 0x05, 0x09]
+        );
+        frame(&mut fram, &[WRITE_ENABLE]);
+        frame(&mut fram, &[WRITE, 0x00, 0x3f, 1, 2, 3]);
+        assert_eq!(read(&mut fram, 0x003f, 3), vec![1, 2, 3]);
+        assert_eq!(frame(&mut fram, &[READ_STATUS, 0]), vec![0xff, 0]);
+    }
+}
@@ -0,0 +1,167 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// State shared by the memory models: the command frame of the current chip
+// select with its page latch, and the write enable latch with the timer of
+// the program or write cycle in progress
+
+use crate::clock::{Clock, SystemClock};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Status register bit set while a program, erase or write is in progress
+pub const STATUS_WIP: u8 = 0x01;
+/// Status register bit set while the write enable latch is set
+pub const STATUS_WEL: u8 = 0x02;
+
+// Fail unless `value`, the `what` of a memory, is a non-zero power of two
+pub(super) fn check_power_of_two(what: &str, value: usize) -> io::Result<()> {
+    if !value.is_power_of_two() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} of {} bytes is not a power of two", what, value),
+        ));
+    }
+    Ok(())
+}
+
+// Command of the current chip select frame, the address it carries and bytes
+// latched for a page write
+pub(super) struct Frame {
+    command: Option<u8>,
+    received: usize,
+    pub(super) address: usize,
+    // Bytes latched by a page write, by offset within the page
+    page: Vec<Option<u8>>,
+}
+
+impl Frame {
+    pub(super) fn new() -> Self {
+        Self {
+            command: None,
+            received: 0,
+            address: 0,
+            page: Vec::new(),
+        }
+    }
+
+    // Start a new frame when chip select is asserted
+    pub(super) fn reset(&mut self) {
+        self.command = None;
+        self.received = 0;
+        self.address = 0;
+        self.page.clear();
+    }
+
+    // Command of the frame ending with chip select released, if any
+    pub(super) fn finish(&mut self) -> Option<u8> {
+        self.command.take()
+    }
+
+    // Number of bytes received after the command
+    pub(super) fn received(&self) -> usize {
+        self.received
+    }
+
+    // Take a byte, returning the command and the number of bytes received
+    // after it before this one, or `None` if the byte was the command
+    pub(super) fn receive(&mut self, mosi: u8) -> Option<(u8, usize)> {
+        match self.command {
+            Some(command) => {
+                self.received += 1;
+                Some((command, self.received - 1))
+            }
+            None => {
+                self.command = Some(mosi);
+                None
+            }
+        }
+    }
+
+    // Shift in an address byte, wrapping around at `size`
+    pub(super) fn shift_address(&mut self, mosi: u8, size: usize) {
+        self.address = ((self.address << 8) | usize::from(mosi)) & (size - 1);
+    }
+
+    // Latch data byte `index` of a page write, wrapping around within the page
+    pub(super) fn latch(&mut self, index: usize, mosi: u8, page_size: usize) {
+        if self.page.is_empty() {
+            self.page.resize(page_size, None);
+        }
+        self.page[(self.address + index) & (page_size - 1)] = Some(mosi);
+    }
+
+    // Addresses and values of the bytes latched by a page write
+    pub(super) fn latched(&self, page_size: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+        let page = self.address & !(page_size - 1);
+        self.page
+            .iter()
+            .enumerate()
+            .filter_map(move |(offset, byte)| byte.map(|byte| (page + offset, byte)))
+    }
+}
+
+// Write enable latch and the program or write cycle in progress
+pub(super) struct WriteCycle {
+    pub(super) enabled: bool,
+    busy_until: Option<Instant>,
+    // Wall clock until attached to a bus
+    clock: Arc<dyn Clock>,
+}
+
+impl WriteCycle {
+    pub(super) fn new() -> Self {
+        Self {
+            enabled: false,
+            busy_until: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub(super) fn attach(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // WIP and WEL status bits, clearing the latch once a cycle has completed
+    pub(super) fn status(&mut self) -> u8 {
+        if let Some(until) = self.busy_until {
+            if self.clock.now() < until {
+                return STATUS_WIP | STATUS_WEL;
+            }
+            self.busy_until = None;
+            self.enabled = false;
+        }
+        if self.enabled {
+            STATUS_WEL
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn is_busy(&mut self) -> bool {
+        self.status() & STATUS_WIP != 0
+    }
+
+    // Start a cycle keeping the memory busy for `duration`
+    pub(super) fn start(&mut self, duration: Duration) {
+        if duration > Duration::from_secs(0) {
+            self.busy_until = Some(self.clock.now() + duration);
+        } else {
+            self.enabled = false;
+        }
+    }
+}
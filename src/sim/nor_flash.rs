@@ -0,0 +1,618 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Model of a JEDEC SPI NOR flash with 3 byte addresses
+//
+// Supported commands:
+//
+//     0x9f read JEDEC ID      0x05 read status register
+//     0x06 write enable       0x04 write disable
+//     0x03 read               0x0b fast read
+//     0x02 page program       0x20 sector erase (4 KiB by default)
+//     0x52 32 KiB block erase 0xd8 block erase (64 KiB by default)
+//     0xc7, 0x60 chip erase
+//
+// Like the real parts, program and erase commands execute once chip select
+// is released and only if the write enable latch is set. Programming can only
+// clear bits, erasing sets them back to 1. While an operation is in progress
+// the status register reports WIP and every other command is ignored; the
+// write enable latch is cleared once it completes.
+
+use crate::clock::Clock;
+use crate::sim::memory::{check_power_of_two, Frame, WriteCycle};
+use crate::sim::SimDevice;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const READ_ID: u8 = 0x9f;
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const WRITE_DISABLE: u8 = 0x04;
+const READ: u8 = 0x03;
+const FAST_READ: u8 = 0x0b;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const BLOCK_ERASE_32K: u8 = 0x52;
+const BLOCK_ERASE: u8 = 0xd8;
+const CHIP_ERASE: u8 = 0xc7;
+const CHIP_ERASE_ALT: u8 = 0x60;
+
+pub use crate::sim::memory::{STATUS_WEL, STATUS_WIP};
+
+const ADDRESS_BYTES: usize = 3;
+
+/// Geometry and timing of a simulated NOR flash
+///
+/// The default resembles a 16 Mbit W25Q16 whose operations complete
+/// instantly. Sizes must be powers of two, with the block size a multiple of
+/// 32 KiB for the 32 KiB block erase to behave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NorFlashConfig {
+    /// Manufacturer, memory type and capacity bytes
+    pub jedec_id: [u8; 3],
+    /// Capacity in bytes, at most 16 MiB
+    pub size: usize,
+    /// Page size in bytes
+    pub page_size: usize,
+    /// Size of the smallest erasable sector in bytes
+    pub sector_size: usize,
+    /// Size of an erase block in bytes
+    pub block_size: usize,
+    /// Duration of a page program
+    pub program_time: Duration,
+    /// Duration of a sector erase
+    pub sector_erase_time: Duration,
+    /// Duration of a block erase
+    pub block_erase_time: Duration,
+    /// Duration of a chip erase
+    pub chip_erase_time: Duration,
+}
+
+impl Default for NorFlashConfig {
+    fn default() -> Self {
+        Self {
+            jedec_id: [0xef, 0x40, 0x15],
+            size: 2 << 20,
+            page_size: 256,
+            sector_size: 4 << 10,
+            block_size: 64 << 10,
+            program_time: Duration::from_secs(0),
+            sector_erase_time: Duration::from_secs(0),
+            block_erase_time: Duration::from_secs(0),
+            chip_erase_time: Duration::from_secs(0),
+        }
+    }
+}
+
+impl NorFlashConfig {
+    /// Set the JEDEC ID
+    ///
+    /// # Argument
+    ///
+    /// `jedec_id` - Manufacturer, memory type and capacity bytes
+    pub fn jedec_id(mut self, jedec_id: [u8; 3]) -> Self {
+        self.jedec_id = jedec_id;
+        self
+    }
+
+    /// Set the geometry
+    ///
+    /// # Arguments
+    ///
+    /// `size` - Capacity in bytes
+    /// `page_size` - Page size in bytes
+    /// `sector_size` - Sector size in bytes
+    /// `block_size` - Block size in bytes
+    pub fn geometry(
+        mut self,
+        size: usize,
+        page_size: usize,
+        sector_size: usize,
+        block_size: usize,
+    ) -> Self {
+        self.size = size;
+        self.page_size = page_size;
+        self.sector_size = sector_size;
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set the duration of a page program
+    ///
+    /// # Argument
+    ///
+    /// `program_time` - Duration of a page program
+    pub fn program_time(mut self, program_time: Duration) -> Self {
+        self.program_time = program_time;
+        self
+    }
+
+    /// Set the durations of the erase operations
+    ///
+    /// # Arguments
+    ///
+    /// `sector` - Duration of a sector erase
+    /// `block` - Duration of a block erase
+    /// `chip` - Duration of a chip erase
+    pub fn erase_times(mut self, sector: Duration, block: Duration, chip: Duration) -> Self {
+        self.sector_erase_time = sector;
+        self.block_erase_time = block;
+        self.chip_erase_time = chip;
+        self
+    }
+}
+
+/// Simulated JEDEC SPI NOR flash
+pub struct NorFlash {
+    config: NorFlashConfig,
+    memory: Vec<u8>,
+    file: Option<File>,
+    file_error: Option<io::Error>,
+    write: WriteCycle,
+    frame: Frame,
+}
+
+impl NorFlash {
+    /// NorFlash constructor, with the memory erased
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` unless every size is a
+    /// non-zero power of two and the capacity is at most 16 MiB.
+    ///
+    /// # Argument
+    ///
+    /// `config` - Geometry and timing
+    pub fn new(config: NorFlashConfig) -> io::Result<Self> {
+        check_power_of_two("size", config.size)?;
+        check_power_of_two("page size", config.page_size)?;
+        check_power_of_two("sector size", config.sector_size)?;
+        check_power_of_two("block size", config.block_size)?;
+        if config.size > 1 << (8 * ADDRESS_BYTES) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("size of {} bytes exceeds 16 MiB", config.size),
+            ));
+        }
+        Ok(Self {
+            config,
+            memory: vec![0xff; config.size],
+            file: None,
+            file_error: None,
+            write: WriteCycle::new(),
+            frame: Frame::new(),
+        })
+    }
+
+    /// NorFlash constructor, keeping the memory in a file
+    ///
+    /// The configuration is checked as by `new`. A new or empty file is
+    /// initialised erased, an existing image must be
+    /// exactly `config.size` bytes. Every program or erase is written through
+    /// to the file.
+    ///
+    /// # Arguments
+    ///
+    /// `config` - Geometry and timing
+    /// `path` - File holding the memory
+    pub fn with_file(config: NorFlashConfig, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut flash = Self::new(config)?;
+        let mut memory = Vec::with_capacity(config.size);
+        file.read_to_end(&mut memory)?;
+        if memory.is_empty() {
+            memory.resize(config.size, 0xff);
+            file.write_all(&memory)?;
+        } else if memory.len() != config.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("image is {} bytes, expected {}", memory.len(), config.size),
+            ));
+        }
+        flash.memory = memory;
+        flash.file = Some(file);
+        Ok(flash)
+    }
+
+    /// Geometry and timing
+    pub fn config(&self) -> &NorFlashConfig {
+        &self.config
+    }
+
+    /// Contents of the memory
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Contents of the memory, for preloading it
+    ///
+    /// Changes made here are not written to a backing file.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// Whether a program or erase is in progress
+    pub fn is_busy(&mut self) -> bool {
+        self.write.is_busy()
+    }
+
+    /// Error writing to the backing file, if one occurred since the last call
+    pub fn take_file_error(&mut self) -> Option<io::Error> {
+        self.file_error.take()
+    }
+
+    fn program(&mut self) {
+        let page_size = self.config.page_size;
+        for (address, byte) in self.frame.latched(page_size) {
+            self.memory[address] &= byte;
+        }
+        let page = self.frame.address & !(page_size - 1);
+        self.persist(page, page_size);
+        self.write.start(self.config.program_time);
+    }
+
+    fn erase(&mut self, size: usize, duration: Duration) {
+        let size = size.min(self.config.size);
+        let start = self.frame.address & !(size - 1);
+        self.memory[start..start + size].fill(0xff);
+        self.persist(start, size);
+        self.write.start(duration);
+    }
+
+    // Next byte of a read, wrapping around at the end of the memory
+    fn read_next(&mut self) -> u8 {
+        let byte = self.memory[self.frame.address];
+        self.frame.address = (self.frame.address + 1) & (self.config.size - 1);
+        byte
+    }
+
+    // Write a changed range through to the backing file
+    fn persist(&mut self, start: usize, len: usize) {
+        if let Some(file) = self.file.as_mut() {
+            let data = &self.memory[start..start + len];
+            let result = file
+                .seek(SeekFrom::Start(start as u64))
+                .and_then(|_| file.write_all(data));
+            if let Err(e) = result {
+                self.file_error = Some(e);
+            }
+        }
+    }
+}
+
+impl SimDevice for NorFlash {
+    fn attach(&mut self, clock: Arc<dyn Clock>) {
+        self.write.attach(clock);
+    }
+
+    fn select(&mut self) {
+        self.frame.reset();
+    }
+
+    fn deselect(&mut self) {
+        let command = match self.frame.finish() {
+            Some(command) => command,
+            None => return,
+        };
+        if self.write.is_busy() {
+            return;
+        }
+        let received = self.frame.received();
+        let addressed = received >= ADDRESS_BYTES;
+        match command {
+            WRITE_ENABLE => self.write.enabled = true,
+            WRITE_DISABLE => self.write.enabled = false,
+            _ if !self.write.enabled => {}
+            PAGE_PROGRAM if received > ADDRESS_BYTES => self.program(),
+            SECTOR_ERASE if addressed => {
+                self.erase(self.config.sector_size, self.config.sector_erase_time)
+            }
+            BLOCK_ERASE_32K if addressed => self.erase(32 << 10, self.config.block_erase_time),
+            BLOCK_ERASE if addressed => {
+                self.erase(self.config.block_size, self.config.block_erase_time)
+            }
+            CHIP_ERASE | CHIP_ERASE_ALT => {
+                self.frame.address = 0;
+                self.erase(self.config.size, self.config.chip_erase_time)
+            }
+            _ => {}
+        }
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        let (command, received) = match self.frame.receive(mosi) {
+            Some(frame) => frame,
+            None => return 0xff,
+        };
+        if command == READ_STATUS {
+            return self.write.status();
+        }
+        if self.write.is_busy() {
+            return 0xff;
+        }
+        if received < ADDRESS_BYTES && command != READ_ID {
+            self.frame.shift_address(mosi, self.config.size);
+            return 0xff;
+        }
+        match command {
+            READ_ID => self.config.jedec_id.get(received).copied().unwrap_or(0),
+            READ => self.read_next(),
+            FAST_READ if received == ADDRESS_BYTES => 0xff,
+            FAST_READ => self.read_next(),
+            PAGE_PROGRAM => {
+                let page_size = self.config.page_size;
+                self.frame.latch(received - ADDRESS_BYTES, mosi, page_size);
+                0xff
+            }
+            _ => 0xff,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn image_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spi-rs-{}-{}.bin", name, std::process::id()))
+    }
+
+    fn small() -> NorFlashConfig {
+        NorFlashConfig::default().geometry(64 << 10, 256, 4 << 10, 32 << 10)
+    }
+
+    // 128 KiB flash with 64 KiB blocks, every byte cleared so erases show
+    fn cleared() -> NorFlash {
+        let config = NorFlashConfig::default().geometry(128 << 10, 256, 4 << 10, 64 << 10);
+        let mut flash = NorFlash::new(config).unwrap();
+        flash.memory_mut().fill(0);
+        flash
+    }
+
+    // Run one chip select frame, returning the bytes answered
+    fn frame(flash: &mut NorFlash, mosi: &[u8]) -> Vec<u8> {
+        flash.select();
+        let miso = mosi.iter().map(|&byte| flash.exchange(byte)).collect();
+        flash.deselect();
+        miso
+    }
+
+    fn addressed(command: u8, address: usize, data: &[u8]) -> Vec<u8> {
+        let mut mosi = vec![
+            command,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+        ];
+        mosi.extend_from_slice(data);
+        mosi
+    }
+
+    fn status(flash: &mut NorFlash) -> u8 {
+        frame(flash, &[READ_STATUS, 0])[1]
+    }
+
+    fn read(flash: &mut NorFlash, address: usize, len: usize) -> Vec<u8> {
+        frame(flash, &addressed(READ, address, &vec![0; len])).split_off(4)
+    }
+
+    // Issue a write enable and then `mosi`
+    fn enabled(flash: &mut NorFlash, mosi: &[u8]) {
+        frame(flash, &[WRITE_ENABLE]);
+        frame(flash, mosi);
+    }
+
+    fn is_filled(memory: &[u8], byte: u8) -> bool {
+        memory.iter().all(|&b| b == byte)
+    }
+
+    #[test]
+    fn geometry_must_be_powers_of_two() {
+        let invalid = |size, page, sector, block| {
+            let config = NorFlashConfig::default().geometry(size, page, sector, block);
+            NorFlash::new(config).err().unwrap().kind()
+        };
+        assert_eq!(invalid(0, 256, 4096, 65536), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            invalid(3 << 20, 256, 4096, 65536),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            invalid(2 << 20, 0, 4096, 65536),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            invalid(2 << 20, 256, 3000, 65536),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(invalid(2 << 20, 256, 4096, 0), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            invalid(32 << 20, 256, 4096, 65536),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(
+            NorFlash::new(NorFlashConfig::default().geometry(16 << 20, 256, 4096, 65536)).is_ok()
+        );
+    }
+
+    #[test]
+    fn jedec_id() {
+        let mut flash =
+            NorFlash::new(NorFlashConfig::default().jedec_id([0xc2, 0x20, 0x16])).unwrap();
+        assert_eq!(
+            frame(&mut flash, &[READ_ID, 0, 0, 0, 0]),
+            vec![0xff, 0xc2, 0x20, 0x16, 0]
+        );
+    }
+
+    #[test]
+    fn writes_need_the_latch() {
+        let mut flash = cleared();
+        frame(&mut flash, &addressed(SECTOR_ERASE, 0, &[]));
+        frame(&mut flash, &[CHIP_ERASE]);
+        assert!(is_filled(flash.memory(), 0));
+
+        frame(&mut flash, &[WRITE_ENABLE]);
+        assert_eq!(status(&mut flash), STATUS_WEL);
+        frame(&mut flash, &[WRITE_DISABLE]);
+        assert_eq!(status(&mut flash), 0);
+        frame(&mut flash, &addressed(SECTOR_ERASE, 0, &[]));
+        assert!(is_filled(flash.memory(), 0));
+
+        let mut flash = NorFlash::new(NorFlashConfig::default()).unwrap();
+        frame(&mut flash, &addressed(PAGE_PROGRAM, 0, &[0x12]));
+        assert_eq!(read(&mut flash, 0, 1), vec![0xff]);
+    }
+
+    #[test]
+    fn page_program_wraps_within_the_page() {
+        let mut flash = NorFlash::new(NorFlashConfig::default()).unwrap();
+        enabled(&mut flash, &addressed(PAGE_PROGRAM, 0x1fe, &[1, 2, 3]));
+        assert_eq!(read(&mut flash, 0x1fe, 3), vec![1, 2, 0xff]);
+        assert_eq!(read(&mut flash, 0x100, 1), vec![3]);
+        // The latch is cleared by the program
+        assert_eq!(status(&mut flash), 0);
+    }
+
+    #[test]
+    fn program_only_clears_bits() {
+        let mut flash = NorFlash::new(NorFlashConfig::default()).unwrap();
+        enabled(&mut flash, &addressed(PAGE_PROGRAM, 0x10, &[0xf0]));
+        enabled(&mut flash, &addressed(PAGE_PROGRAM, 0x10, &[0x3c]));
+        assert_eq!(read(&mut flash, 0x10, 1), vec![0x30]);
+    }
+
+    #[test]
+    fn erases_cover_their_aligned_range() {
+        let mut flash = cleared();
+        enabled(&mut flash, &addressed(SECTOR_ERASE, 0x1234, &[]));
+        assert!(is_filled(&flash.memory()[0x1000..0x2000], 0xff));
+        assert!(is_filled(&flash.memory()[..0x1000], 0));
+        assert!(is_filled(&flash.memory()[0x2000..], 0));
+
+        let mut flash = cleared();
+        enabled(&mut flash, &addressed(BLOCK_ERASE_32K, 0x9000, &[]));
+        assert!(is_filled(&flash.memory()[0x8000..0x10000], 0xff));
+        assert!(is_filled(&flash.memory()[..0x8000], 0));
+        assert!(is_filled(&flash.memory()[0x10000..], 0));
+
+        let mut flash = cleared();
+        enabled(&mut flash, &addressed(BLOCK_ERASE, 0x12345, &[]));
+        assert!(is_filled(&flash.memory()[0x10000..], 0xff));
+        assert!(is_filled(&flash.memory()[..0x10000], 0));
+
+        for &command in &[CHIP_ERASE, CHIP_ERASE_ALT] {
+            let mut flash = cleared();
+            enabled(&mut flash, &[command]);
+            assert!(is_filled(flash.memory(), 0xff));
+            assert_eq!(status(&mut flash), 0);
+        }
+    }
+
+    #[test]
+    fn fast_read_skips_a_dummy_byte() {
+        let mut flash = NorFlash::new(NorFlashConfig::default()).unwrap();
+        flash.memory_mut()[0x100..0x102].copy_from_slice(&[0x11, 0x22]);
+        assert_eq!(
+            frame(&mut flash, &addressed(FAST_READ, 0x100, &[0, 0, 0])),
+            vec![0xff, 0xff, 0xff, 0xff, 0xff, 0x11, 0x22]
+        );
+    }
+
+    #[test]
+    fn commands_are_ignored_while_busy() {
+        let config = NorFlashConfig::default().program_time(Duration::from_millis(1));
+        let mut flash = NorFlash::new(config).unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        flash.attach(clock.clone());
+        flash.memory_mut()[0x10] = 0x5a;
+        enabled(&mut flash, &addressed(PAGE_PROGRAM, 0, &[0x00]));
+        assert!(flash.is_busy());
+        assert_eq!(status(&mut flash), STATUS_WIP | STATUS_WEL);
+        assert_eq!(read(&mut flash, 0x10, 1), vec![0xff]);
+        assert_eq!(frame(&mut flash, &[READ_ID, 0]), vec![0xff, 0xff]);
+        frame(&mut flash, &[WRITE_DISABLE]);
+        frame(&mut flash, &addressed(PAGE_PROGRAM, 0x20, &[0x00]));
+        frame(&mut flash, &[CHIP_ERASE]);
+
+        clock.advance(Duration::from_millis(1));
+        assert!(!flash.is_busy());
+        assert_eq!(status(&mut flash), 0);
+        assert_eq!(read(&mut flash, 0, 1), vec![0x00]);
+        assert_eq!(read(&mut flash, 0x10, 1), vec![0x5a]);
+        assert_eq!(read(&mut flash, 0x20, 1), vec![0xff]);
+    }
+
+    #[test]
+    fn new_file_is_created_erased() {
+        let path = image_path("new");
+        let _ = fs::remove_file(&path);
+        let flash = NorFlash::with_file(small(), &path).unwrap();
+        assert!(flash.memory().iter().all(|&b| b == 0xff));
+        assert_eq!(fs::read(&path).unwrap(), vec![0xff; 64 << 10]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn existing_image_is_loaded() {
+        let path = image_path("existing");
+        let mut image = vec![0xff; 64 << 10];
+        image[..4].copy_from_slice(&[1, 2, 3, 4]);
+        fs::write(&path, &image).unwrap();
+        let flash = NorFlash::with_file(small(), &path).unwrap();
+        assert_eq!(flash.memory(), image.as_slice());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mismatched_image_is_rejected_untouched() {
+        let path = image_path("mismatched");
+        fs::write(&path, vec![0x5a; 1000]).unwrap();
+        let err = NorFlash::with_file(small(), &path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read(&path).unwrap(), vec![0x5a; 1000]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn erase_is_written_through() {
+        let path = image_path("persist");
+        fs::write(&path, vec![0; 64 << 10]).unwrap();
+        let mut flash = NorFlash::with_file(small(), &path).unwrap();
+        flash.select();
+        flash.exchange(WRITE_ENABLE);
+        flash.deselect();
+        flash.select();
+        for byte in &[SECTOR_ERASE, 0x00, 0x10, 0x00] {
+            flash.exchange(*byte);
+        }
+        flash.deselect();
+        assert!(flash.take_file_error().is_none());
+        let image = fs::read(&path).unwrap();
+        assert!(image[0x1000..0x2000].iter().all(|&b| b == 0xff));
+        assert!(image[..0x1000].iter().all(|&b| b == 0));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Stream wrapper putting a deadline on every operation
+
+use crate::clock::Clock;
+use crate::worker::{stopped, OwnedSegments, Worker};
+use crate::{Error, Operation, Result, Segment, SpiConfig, SpiStream, Stream};
+use spidev::SpiModeFlags;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Health of a `TimeoutStream`, shared with supervising code
+///
+/// Clones share the state, so a handle can be kept after the stream has been
+/// moved into a `Connection`.
+#[derive(Debug, Clone)]
+pub struct Health {
+    healthy: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// Whether every operation so far completed in time
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    fn mark_unhealthy(&self) {
+        self.healthy.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Stream wrapper failing operations which do not complete in time
+///
+/// The wrapped stream runs on a dedicated worker thread. An operation still
+/// running at its deadline fails with `Error::Timeout` and marks the stream
+/// unhealthy, after which every operation fails with `Error::Unhealthy`. The
+/// hung call keeps its thread, so the device should be reopened or power
+/// cycled by whoever watches `health()`.
+///
+/// `Stream::lock` holds the wrapped stream on the worker thread. Waiting for
+/// it and every operation on the held stream are subject to the deadline.
+pub struct TimeoutStream {
+    worker: Worker,
+    timeout: Duration,
+    health: Health,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimeoutStream {
+    /// TimeoutStream constructor
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream to move onto the worker thread
+    /// `timeout` - Deadline of each operation
+    pub fn new(stream: Box<dyn Stream + Send>, timeout: Duration) -> Self {
+        let clock = stream.clock();
+        Self {
+            worker: Worker::spawn(stream),
+            timeout,
+            health: Health {
+                healthy: Arc::new(AtomicBool::new(true)),
+            },
+            clock,
+        }
+    }
+
+    /// Convenience constructor for creating a TimeoutStream with a SPIDEV
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to SPI device
+    /// `bpw` - Bits per word
+    /// `max_speed` - Max speed in Hz
+    /// `mode` - SPI Mode
+    /// `timeout` - Deadline of each operation
+    pub fn from_path(
+        path: String,
+        bpw: u8,
+        max_speed: u32,
+        mode: SpiModeFlags,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let stream = SpiStream::new(path, bpw, max_speed, mode)?;
+        Ok(Self::new(Box::new(stream), timeout))
+    }
+
+    /// Handle to the health of the stream
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    /// Change the deadline of each operation
+    ///
+    /// # Argument
+    ///
+    /// `timeout` - Deadline of each operation
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Run `f` against the stream on the worker thread, waiting until the deadline
+    fn run<R, F>(&self, op: Operation, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut dyn Stream) -> Result<R> + Send + 'static,
+    {
+        if !self.health.is_healthy() {
+            return Err(Error::Unhealthy);
+        }
+        let (tx, rx) = mpsc::channel();
+        self.worker.submit(move |stream| {
+            let _ = tx.send(f(stream));
+        })?;
+        self.wait(op, rx)
+    }
+
+    // Wait for the result of a job until the deadline
+    fn wait<R>(&self, op: Operation, rx: mpsc::Receiver<Result<R>>) -> Result<R> {
+        match rx.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.health.mark_unhealthy();
+                Err(Error::Timeout { op })
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+}
+
+impl Stream for TimeoutStream {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let data = data.to_vec();
+        self.run(Operation::Write, move |stream| stream.write(&data))
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.run(Operation::Read, move |stream| stream.read(len))
+    }
+
+    fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let data = data.to_vec();
+        self.run(Operation::Transfer, move |stream| stream.transfer(&data))
+    }
+
+    fn transaction(&self, segments: &[Segment]) -> Result<Vec<Vec<u8>>> {
+        let segments = OwnedSegments::new(segments);
+        self.run(Operation::Transaction, move |stream| {
+            stream.transaction(&segments.segments())
+        })
+    }
+
+    fn set_mode(&mut self, mode: SpiModeFlags) -> Result<()> {
+        self.run(Operation::Configure, move |stream| stream.set_mode(mode))
+    }
+
+    fn set_speed(&mut self, max_speed: u32) -> Result<()> {
+        self.run(Operation::Configure, move |stream| {
+            stream.set_speed(max_speed)
+        })
+    }
+
+    fn set_bits_per_word(&mut self, bpw: u8) -> Result<()> {
+        self.run(Operation::Configure, move |stream| {
+            stream.set_bits_per_word(bpw)
+        })
+    }
+
+    fn config(&self) -> Result<SpiConfig> {
+        self.run(Operation::Configure, |stream| stream.config())
+    }
+
+    fn lock(&self) -> Result<Option<Box<dyn Stream + '_>>> {
+        if !self.health.is_healthy() {
+            return Err(Error::Unhealthy);
+        }
+        let (tx, rx) = mpsc::channel();
+        self.worker.hold(move |held| {
+            let _ = tx.send(held);
+        })?;
+        // Holding the stream starts a sequence of operations
+        let held = self.wait(Operation::Transaction, rx)?;
+        Ok(held.map(|worker| {
+            Box::new(TimeoutStream {
+                worker,
+                timeout: self.timeout,
+                health: self.health.clone(),
+                clock: self.clock.clone(),
+            }) as Box<dyn Stream + '_>
+        }))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Expectation, MockStream, SharedBus, SimulatedBus};
+    use std::thread;
+
+    // Stream whose transfers hang for a while
+    struct Slow(Duration);
+
+    impl Stream for Slow {
+        fn write(&mut self, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+            Ok(vec![0; len])
+        }
+
+        fn transfer(&self, data: &[u8]) -> Result<Vec<u8>> {
+            thread::sleep(self.0);
+            Ok(data.to_vec())
+        }
+    }
+
+    #[test]
+    fn operations_pass_through() {
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x01]),
+            Expectation::transfer(&[0x02], &[0x03]),
+        ]);
+        let mut stream = TimeoutStream::new(Box::new(mock.clone()), Duration::from_secs(1));
+        stream.write(&[0x01]).unwrap();
+        assert_eq!(stream.transfer(&[0x02]).unwrap(), vec![0x03]);
+        stream.set_speed(1_000_000).unwrap();
+        assert_eq!(stream.config().unwrap().max_speed_hz, 1_000_000);
+        assert!(stream.health().is_healthy());
+        mock.done();
+    }
+
+    #[test]
+    fn hung_operation_marks_unhealthy() {
+        let mut stream = TimeoutStream::new(
+            Box::new(Slow(Duration::from_millis(500))),
+            Duration::from_millis(20),
+        );
+        let health = stream.health();
+        assert!(matches!(
+            stream.transfer(&[0x01]),
+            Err(Error::Timeout {
+                op: Operation::Transfer
+            })
+        ));
+        assert!(!health.is_healthy());
+        assert!(matches!(stream.write(&[0x01]), Err(Error::Unhealthy)));
+    }
+
+    #[test]
+    fn clock_is_forwarded() {
+        let bus = SimulatedBus::new();
+        let stream = TimeoutStream::new(Box::new(bus.clone()), Duration::from_secs(1));
+        stream.clock().sleep(Duration::from_secs(3));
+        assert_eq!(bus.virtual_clock().elapsed(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn lock_holds_the_bus() {
+        let mock = MockStream::new(vec![
+            Expectation::write(&[0x06]),
+            Expectation::read(&[0x02]),
+            Expectation::write(&[0x04]),
+        ]);
+        let bus = SharedBus::new(Box::new(mock.clone()));
+        let stream = TimeoutStream::new(
+            Box::new(bus.device(SpiConfig::default())),
+            Duration::from_secs(1),
+        );
+        let mut connection = Connection::new(Box::new(stream));
+        let status = connection
+            .exclusive(|stream| {
+                // Already holding the bus, so there is nothing left to lock
+                assert!(stream.lock()?.is_none());
+                stream.write(&[0x06])?;
+                stream.read(1)
+            })
+            .unwrap();
+        assert_eq!(status, vec![0x02]);
+        // Releasing the held stream frees the bus for other devices
+        bus.device(SpiConfig::default()).write(&[0x04]).unwrap();
+        mock.done();
+    }
+}
@@ -0,0 +1,117 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Dedicated thread owning a Stream and running blocking jobs against it
+
+use crate::{Error, Result, Segment, SegmentKind, Stream};
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+type Job = Box<dyn FnOnce(&mut dyn Stream) + Send>;
+
+pub(crate) struct Worker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Worker {
+    // Move `stream` onto a new thread, which exits once the Worker is dropped
+    pub(crate) fn spawn(mut stream: Box<dyn Stream + Send>) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("spi-worker".to_string())
+            .spawn(move || {
+                for job in queue {
+                    job(stream.as_mut());
+                }
+            })
+            .expect("failed to spawn SPI worker thread");
+        Self { jobs }
+    }
+
+    // Queue `job`, failing if the thread has died
+    pub(crate) fn submit(
+        &self,
+        job: impl FnOnce(&mut dyn Stream) + Send + 'static,
+    ) -> Result<()> {
+        self.jobs.send(Box::new(job)).map_err(|_| stopped())
+    }
+
+    // Lock the stream on the thread and pass `reply` a Worker whose jobs run
+    // against the held stream, which stays held until that Worker is dropped.
+    // `reply` gets `None` if the stream is not shared.
+    pub(crate) fn hold(
+        &self,
+        reply: impl FnOnce(Result<Option<Worker>>) + Send + 'static,
+    ) -> Result<()> {
+        self.submit(move |stream| {
+            let mut held = match stream.lock() {
+                Ok(Some(held)) => held,
+                Ok(None) => return reply(Ok(None)),
+                Err(e) => return reply(Err(e)),
+            };
+            let (jobs, queue) = mpsc::channel::<Job>();
+            reply(Ok(Some(Worker { jobs })));
+            for job in queue {
+                job(held.as_mut());
+            }
+        })
+    }
+}
+
+pub(crate) fn stopped() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "SPI worker thread has stopped",
+    ))
+}
+
+// Transaction segments with their payload copied, so they can be moved to the worker
+pub(crate) struct OwnedSegments {
+    segments: Vec<(Segment<'static>, Vec<u8>)>,
+}
+
+impl OwnedSegments {
+    pub(crate) fn new(segments: &[Segment]) -> Self {
+        Self {
+            segments: segments
+                .iter()
+                .map(|segment| {
+                    let (kind, data) = match segment.kind {
+                        SegmentKind::Write(data) => (SegmentKind::Write(&[]), data.to_vec()),
+                        SegmentKind::Read(len) => (SegmentKind::Read(len), Vec::new()),
+                        SegmentKind::Transfer(data) => (SegmentKind::Transfer(&[]), data.to_vec()),
+                    };
+                    (Segment { kind, ..*segment }, data)
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn segments(&self) -> Vec<Segment<'_>> {
+        self.segments
+            .iter()
+            .map(|(segment, data)| {
+                let kind = match segment.kind {
+                    SegmentKind::Write(_) => SegmentKind::Write(data),
+                    SegmentKind::Read(len) => SegmentKind::Read(len),
+                    SegmentKind::Transfer(_) => SegmentKind::Transfer(data),
+                };
+                Segment { kind, ..*segment }
+            })
+            .collect()
+    }
+}